@@ -0,0 +1,97 @@
+use log::{info, warn};
+use ssh2::{MethodType, Session};
+
+use crate::{
+    cli::{Options, Test},
+    config::SessionPreferences,
+    connect::{connect_session, explain_disconnect},
+    summary::Record,
+    tests::{remove_remote_file, run_echo_test, run_speed_test},
+    timeline::Timeline,
+    util::Formatter,
+};
+
+// All ciphers supported by the local libssh2
+pub fn supported_ciphers() -> Result<Vec<String>, String> {
+    let session = Session::new().map_err(|e| e.to_string())?;
+    let ciphers = session
+        .supported_algs(MethodType::CryptCs)
+        .map_err(|e| e.to_string())?;
+    Ok(ciphers.into_iter().map(String::from).collect())
+}
+
+// Connect once per cipher and run the selected tests, one group per cipher
+pub fn run_cipher_benchmark(
+    opts: &Options,
+    preferences: &SessionPreferences,
+    ciphers: &[String],
+    cleanup: bool,
+    formatter: &Formatter,
+) -> Vec<Vec<Record>> {
+    info!("Running cipher benchmark");
+    ciphers
+        .iter()
+        .map(|cipher| {
+            info!("Benchmarking cipher {cipher}");
+            match benchmark_cipher(opts, preferences, cipher, cleanup, formatter) {
+                Ok(records) => records,
+                Err(e) => {
+                    let e = explain_disconnect(e);
+                    warn!("Failed to benchmark cipher {cipher}: {e}");
+                    vec![Record::new(cipher.as_str(), "Error", e)]
+                }
+            }
+        })
+        .collect()
+}
+
+fn benchmark_cipher(
+    opts: &Options,
+    preferences: &SessionPreferences,
+    cipher: &str,
+    cleanup: bool,
+    formatter: &Formatter,
+) -> Result<Vec<Record>, String> {
+    let preferences = SessionPreferences {
+        ciphers: Some(cipher.to_string()),
+        ..preferences.clone()
+    };
+    let (session, ssh_connect_time, _) =
+        connect_session(opts, &preferences, &mut Timeline::start())?;
+    // The server may have picked another cipher from our preference list
+    if let Some(negotiated) = session.methods(MethodType::CryptCs) {
+        if negotiated != cipher {
+            return Err(format!("Negotiated {negotiated} instead"));
+        }
+    }
+    let mut records = vec![Record::new(
+        cipher,
+        "Connect time",
+        formatter.format_duration(ssh_connect_time),
+    )];
+    if opts.run_tests == Test::Echo || opts.run_tests == Test::Both {
+        let result = run_echo_test(&session, opts, formatter)?;
+        records.push(Record::new(cipher, "Latency", result.avg_latency));
+    }
+    if opts.run_tests == Test::Speed || opts.run_tests == Test::Both {
+        let result = run_speed_test(&session, opts, formatter);
+        if cleanup {
+            if let Some(remote_file) = &opts.remote_file {
+                if let Err(e) = remove_remote_file(&session, remote_file) {
+                    warn!("Failed to remove remote file {remote_file:?}: {e}");
+                }
+            }
+        }
+        let result = result?;
+        records.push(Record::new(
+            cipher,
+            "Upload",
+            result
+                .upload
+                .map_or("Skipped".to_string(), |upload| upload.speed),
+        ));
+        records.push(Record::new(cipher, "Download", result.download.speed));
+    }
+    let _ = session.disconnect(None, "sshping cipher benchmark", None);
+    Ok(records)
+}
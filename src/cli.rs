@@ -1,8 +1,12 @@
-use std::path::PathBuf;
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
 
 use bytesize::ByteSize;
 use clap::{
-    builder::{styling::AnsiColor, Styles},
+    builder::{styling::AnsiColor, RangedI64ValueParser, RangedU64ValueParser, Styles},
     crate_authors, crate_description, crate_name, crate_version, ArgAction, Parser, ValueEnum,
     ValueHint,
 };
@@ -13,7 +17,7 @@ use whoami::username;
 use crate::style::TableStyle;
 
 // Define options struct
-#[derive(Parser, Debug)]
+#[derive(Parser, Clone, Debug)]
 #[command(name = crate_name!())]
 #[command(version = crate_version!())]
 #[command(about = crate_description!())]
@@ -21,13 +25,15 @@ use crate::style::TableStyle;
 #[command(author = crate_authors!())]
 #[command(styles = get_styles())]
 pub struct Options {
-    /// [user@]host[:port]
+    /// [user@]host[:port], with IPv6 addresses in brackets if a port is given
     #[arg(value_parser = parse_target, value_hint = ValueHint::Hostname, group = "main_action", default_value = "")]
     pub target: Target,
 
     /// Read the ssh config file FILE for options
     ///
-    /// We get the user, host, port and identity file from ssh config
+    /// We get the user, host, port, identity file, compression,
+    /// connection attempts, address family, environment to set and preferred
+    /// ciphers, MACs, key exchange and host key algorithms from ssh config
     ///
     /// NOTE: Options like bind address, proxy jump, etc. are not supported
     #[arg(
@@ -40,6 +46,43 @@ pub struct Options {
     )]
     pub config: PathBuf,
 
+    /// Fail if the ssh config or ssh options set directives that are ignored
+    ///
+    /// Lists every ignored directive for the target, such as ProxyJump or
+    /// ForwardAgent, instead of silently skipping them
+    #[arg(long)]
+    pub strict_config: bool,
+
+    /// Print each resolved setting of the target and where it came from
+    ///
+    /// Shows whether the host, user, port, identity and proxy jump come from
+    /// the command line, a line of the ssh config file, --ssh-option or the
+    /// defaults
+    #[arg(long)]
+    pub debug_config: bool,
+
+    /// Set ssh config option KEY to VALUE, can be used multiple times
+    ///
+    /// Options are applied as if they appeared in a matching Host block,
+    /// taking precedence over the ssh config file
+    ///
+    /// Examples of possible value: User=root, Port=2222, "HostName 10.0.0.1"
+    #[arg(
+        short = 'O',
+        long = "ssh-option",
+        value_name = "KEY=VALUE",
+        value_parser = parse_ssh_option,
+        value_hint = ValueHint::Other
+    )]
+    pub ssh_options: Vec<String>,
+
+    /// Use algorithm PRESET for ciphers, MACs, key exchange and host keys
+    ///
+    /// Algorithms from ssh config or --ssh-option take precedence
+    /// over the preset
+    #[arg(long, value_enum, value_name = "PRESET", value_hint = ValueHint::Other)]
+    pub preset: Option<Preset>,
+
     /// Use identity FILE, i.e., ssh private key file
     ///
     /// Typically ~/.ssh/id_<algo> where <algo> is rsa, dsa, ecdsa, etc.
@@ -55,6 +98,24 @@ pub struct Options {
     )]
     pub identity: Option<PathBuf>,
 
+    /// Use certificate FILE signed for the identity
+    ///
+    /// Defaults to <identity>-cert.pub if it exists
+    #[arg(
+        long,
+        value_name = "FILE",
+        value_parser = parse_local_path,
+        value_hint = ValueHint::FilePath
+    )]
+    pub certificate: Option<PathBuf>,
+
+    /// Append every authentication attempt to FILE as JSON lines
+    ///
+    /// Each line records the method, whether it succeeded, how long it took
+    /// and the error if any
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub auth_log: Option<PathBuf>,
+
     /// Use password PWD for authentication (not recommended)
     ///
     /// WARNING: Password authentication is not recommended for security reasons
@@ -63,24 +124,234 @@ pub struct Options {
     #[arg(short, long, value_name = "PWD", value_hint = ValueHint::Other)]
     pub password: Option<String>,
 
+    /// Try authentication METHODS strictly in this order
+    ///
+    /// Public key tries the agent before the identity, the password is also
+    /// used to answer keyboard-interactive prompts, and methods left out are
+    /// never tried
+    ///
+    /// Examples of possible value: publickey,keyboard-interactive,password
+    #[arg(
+        long,
+        value_enum,
+        value_name = "METHODS",
+        value_delimiter = ',',
+        default_value = "publickey,password",
+        value_hint = ValueHint::Other
+    )]
+    pub preferred_authentications: Vec<AuthMethod>,
+
+    /// Fail instead of falling back to password authentication
+    ///
+    /// Catches broken key setups where policy forbids passwords, the
+    /// password is then only used as the passphrase of the identity
+    #[arg(long, visible_alias = "fail-if-password-used")]
+    pub require_pubkey: bool,
+
     /// Time limit for ssh connection in seconds
     ///
     /// Timeout for all the ssh operations including authentication
+    /// and each read or write during the speed test
     #[arg(
         short = 'T',
         long,
         value_name = "SECONDS",
         default_value_t = 10.0,
+        value_parser = parse_seconds,
         value_hint = ValueHint::Other
     )]
     pub ssh_timeout: f64,
 
+    /// Time limit in seconds for the TCP connect and SSH handshake
+    ///
+    /// Marks unreachable hosts quickly while still allowing --ssh-timeout
+    /// for authentication and the tests
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        value_parser = parse_seconds,
+        value_hint = ValueHint::Other
+    )]
+    pub max_connect_time: Option<f64>,
+
+    /// Time limit in seconds for the server to send its SSH banner
+    ///
+    /// Fails fast when the server or a middlebox stalls before the handshake
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        value_parser = parse_seconds,
+        value_hint = ValueHint::Other
+    )]
+    pub banner_timeout: Option<f64>,
+
+    /// Warn if the SSH handshake takes longer than MS milliseconds
+    ///
+    /// A slow key exchange hints at an overloaded server, weak entropy
+    /// or an inspecting middlebox
+    #[arg(long, value_name = "MS", value_hint = ValueHint::Other)]
+    pub warn_handshake_ms: Option<u64>,
+
+    /// Run local CMD after successfully connecting to the server
+    ///
+    /// Tokens %h, %p and %r are replaced by the host, port and remote user,
+    /// %% by a literal '%'
+    ///
+    /// NOTE: The command runs on this machine with your privileges
+    #[arg(long, value_name = "CMD", value_hint = ValueHint::CommandString)]
+    pub local_command: Option<String>,
+
+    /// Hold an exclusive lock on FILE while running
+    ///
+    /// Keeps overlapping runs, e.g. from cron, from interfering with each
+    /// other. The lock is released on exit, the file is left in place
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub lock_file: Option<PathBuf>,
+
+    /// Wait up to SECONDS for the lock instead of failing right away
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        requires = "lock_file",
+        value_parser = parse_wait_seconds,
+        value_hint = ValueHint::Other
+    )]
+    pub lock_timeout: Option<f64>,
+
+    /// Use the already connected socket FD instead of connecting
+    ///
+    /// Useful when a supervisor such as systemd hands over the connection,
+    /// the target is then only used for authentication. FD must be a
+    /// connected TCP socket other than the standard streams 0, 1 and 2
+    #[cfg(unix)]
+    #[arg(
+        long,
+        value_name = "FD",
+        value_parser = RangedI64ValueParser::<i32>::new().range(3..),
+        conflicts_with_all = [
+            "connect_repeat",
+            "compare",
+            "warmup_connections",
+            "audit_auth",
+            "benchmark_ciphers",
+            "reconnect_per_test",
+            "resolver",
+            "https_proxy"
+        ],
+        value_hint = ValueHint::Other
+    )]
+    pub fd: Option<i32>,
+
+    /// Resolve the host through the DNS server at IP[:PORT]
+    ///
+    /// Bypasses the system resolver, e.g. to check split-horizon DNS
+    ///
+    /// Examples of possible value: 10.0.0.53, [2001:db8::53]:5353
+    #[arg(
+        long,
+        value_name = "IP[:PORT]",
+        value_parser = parse_resolver,
+        value_hint = ValueHint::Other
+    )]
+    pub resolver: Option<SocketAddr>,
+
+    /// Tunnel the connection through the HTTP proxy at HOST:PORT
+    ///
+    /// Uses the CONNECT method, with Basic authentication if credentials
+    /// are given. The proxy resolves the target, so --resolver is not used
+    ///
+    /// Examples of possible value: proxy.corp:3128, alice:secret@10.0.0.1:8080
+    #[arg(
+        long,
+        value_name = "[USER:PASSWORD@]HOST:PORT",
+        value_parser = parse_http_proxy,
+        value_hint = ValueHint::Other
+    )]
+    pub https_proxy: Option<HttpProxy>,
+
+    /// Route the connection over network interface NAME
+    ///
+    /// Binds the socket to the device with SO_BINDTODEVICE, which also
+    /// works when the same address exists on several interfaces
+    ///
+    /// NOTE: Linux only, and usually requires CAP_NET_RAW or root
+    ///
+    /// Examples of possible value: eth1, wg0
+    #[cfg(target_os = "linux")]
+    #[arg(
+        long,
+        value_name = "NAME",
+        conflicts_with = "fd",
+        value_hint = ValueHint::Other
+    )]
+    pub interface: Option<String>,
+
+    /// Only benchmark connection setup by connecting COUNT times
+    ///
+    /// Each connection is established, authenticated and closed in turn,
+    /// reporting the connection rate and setup time statistics
+    /// instead of running any test
+    #[arg(
+        long,
+        value_name = "COUNT",
+        value_parser = RangedU64ValueParser::<usize>::new().range(1..),
+        value_hint = ValueHint::Other
+    )]
+    pub connect_repeat: Option<usize>,
+
+    /// Connect and disconnect COUNT times before measuring anything
+    ///
+    /// Warms up DNS caches, ARP entries and server-side state, so that the
+    /// results reflect a steady state rather than a cold start
+    #[arg(
+        long,
+        value_name = "COUNT",
+        default_value_t = 0,
+        value_hint = ValueHint::Other
+    )]
+    pub warmup_connections: usize,
+
+    /// Only compare TARGET_A against TARGET_B side by side
+    ///
+    /// Runs the selected tests against both targets in turn and reports
+    /// each metric along with the ratio of the second to the first
+    #[arg(
+        long,
+        num_args = 2,
+        value_names = ["TARGET_A", "TARGET_B"],
+        value_parser = parse_target,
+        conflicts_with_all = ["connect_repeat", "audit_auth", "benchmark_ciphers"],
+        value_hint = ValueHint::Hostname
+    )]
+    pub compare: Option<Vec<Target>>,
+
+    /// Only audit which authentication methods the server accepts
+    ///
+    /// Tries the agent, identity, password and keyboard-interactive
+    /// methods each on a separate connection instead of stopping at the
+    /// first success, then reports the outcome of each
+    #[arg(long)]
+    pub audit_auth: bool,
+
+    /// Benchmark each CIPHER with a separate connection
+    ///
+    /// Runs the selected tests once per cipher and compares the results,
+    /// benchmarking all ciphers supported by libssh2 if none is given
+    #[arg(
+        long,
+        value_name = "CIPHER",
+        num_args = 0..,
+        value_delimiter = ',',
+        value_hint = ValueHint::Other
+    )]
+    pub benchmark_ciphers: Option<Vec<String>>,
+
     /// Run TEST
     ///
     /// Echo test: sends a large number of characters to the remote server
     /// and measures the latency
     ///
-    /// Speed test: sends/receives a large file through scp
+    /// Speed test: sends/receives a large file through scp or sftp
     /// and measures the throughput
     #[arg(
         short,
@@ -92,15 +363,66 @@ pub struct Options {
     )]
     pub run_tests: Test,
 
+    /// Use a fresh connection for each test
+    ///
+    /// Keeps the echo test from warming up the connection used by the
+    /// speed test, reporting the connect time of each
+    #[arg(long)]
+    pub reconnect_per_test: bool,
+
+    /// Check that the remote has the tools the selected tests need
+    ///
+    /// Reports missing tools up front instead of failing inside a test
+    #[arg(long)]
+    pub preflight: bool,
+
+    /// Only sweep echo payloads of each SIZE, reporting latency per size
+    ///
+    /// Sends COUNT payloads of each size and waits for all of their bytes
+    /// to be echoed, revealing where packetization or windowing kicks in
+    ///
+    /// Defaults to 1,64,256,1024,4096 bytes if no size is given
+    #[arg(
+        long,
+        value_name = "SIZE",
+        num_args = 0..,
+        value_delimiter = ',',
+        value_parser = parse_file_size,
+        default_missing_values = ["1", "64", "256", "1024", "4096"],
+        value_hint = ValueHint::Other
+    )]
+    pub payload_sweep: Option<Vec<u64>>,
+
+    /// Only measure command execution latency by running `true` repeatedly
+    ///
+    /// Times opening a channel until the command exits, without a
+    /// pseudo-terminal, shell or echo program, so it works on most accounts.
+    /// The command runs --char-count times
+    #[arg(long)]
+    pub probe_only: bool,
+
     /// Number of characters to echo
-    #[arg(short, long, value_name = "COUNT", default_value_t = 1000, value_hint = ValueHint::Other)]
+    ///
+    /// Also the number of probes for --probe-only and of echoes per payload
+    /// size for --payload-sweep
+    #[arg(
+        short,
+        long,
+        value_name = "COUNT",
+        default_value_t = 1000,
+        value_parser = RangedU64ValueParser::<usize>::new().range(1..),
+        value_hint = ValueHint::Other
+    )]
     pub char_count: usize,
 
     /// Use CMD for echo command
     ///
     /// Should capture all following input and discard them safely
     ///
-    /// NOTE: This command should not exit immediately
+    /// NOTE: This command should not exit immediately,
+    /// see --echo-test-ignore-exit for commands that exit
+    ///
+    /// Tokens %h (host), %u (user), %p (port) and %% are expanded
     #[arg(
         short,
         long,
@@ -110,12 +432,184 @@ pub struct Options {
     )]
     pub echo_cmd: String,
 
+    /// End the echo test quietly when the echo command exits
+    ///
+    /// Keeps the echoes collected so far instead of failing the test
+    #[arg(long)]
+    pub echo_test_ignore_exit: bool,
+
     /// Time limit for echo test in seconds
     ///
     /// Early termination of the echo test if exceeding this time limit
     #[arg(short = 't', long, value_name = "SECONDS", value_hint = ValueHint::Other)]
     pub echo_timeout: Option<f64>,
 
+    /// Echo for DURATION instead of a number of characters
+    ///
+    /// Runs the echo test for a fixed time, reporting as many characters
+    /// as fit in it
+    ///
+    /// Examples of possible value: 30s, 2m
+    #[arg(
+        long,
+        value_name = "DURATION",
+        conflicts_with_all = ["char_count", "echo_timeout"],
+        value_parser = parse_duration,
+        value_hint = ValueHint::Other
+    )]
+    pub echo_duration: Option<Duration>,
+
+    /// Stop the echo test early once the average latency is known to PRECISION
+    ///
+    /// Stops when the 95% confidence interval of the average is within
+    /// PRECISION of it, as a fraction, sending at most --char-count echoes
+    ///
+    /// Defaults to 0.05 if no precision is given
+    #[arg(
+        long,
+        value_name = "PRECISION",
+        num_args = 0..=1,
+        default_missing_value = "0.05",
+        value_hint = ValueHint::Other
+    )]
+    pub adaptive_echo: Option<f64>,
+
+    /// Wait MS milliseconds between echo characters
+    ///
+    /// Simulates typing cadence instead of saturating the link,
+    /// the wait is not counted in the measured latency
+    #[arg(long, value_name = "MS", value_hint = ValueHint::Other)]
+    pub echo_interval: Option<u64>,
+
+    /// Run the echo command directly instead of in an interactive shell
+    ///
+    /// The command must write back what it reads, e.g. cat or a custom
+    /// echo server, so latency is measured through the program itself
+    /// rather than the terminal
+    ///
+    /// Examples: --echo-exec --echo-cmd cat
+    #[arg(long)]
+    pub echo_exec: bool,
+
+    /// Set environment variable KEY to VALUE for the echo shell and commands
+    ///
+    /// Can be used multiple times, and takes precedence over SetEnv in ssh
+    /// config. The server only accepts variables listed in its AcceptEnv
+    ///
+    /// Examples of possible value: LANG=C.UTF-8
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_env_var, value_hint = ValueHint::Other)]
+    pub setenv: Vec<(String, String)>,
+
+    /// Terminal TYPE requested for the echo shell, seen remotely as $TERM
+    ///
+    /// Examples of possible value: xterm-256color, vt100, dumb
+    #[arg(long, value_name = "TYPE", default_value = "sshping", value_hint = ValueHint::Other)]
+    pub term: String,
+
+    /// Size of the terminal requested for the echo shell, as COLSxROWS
+    ///
+    /// Prompts and line wrapping may depend on it
+    ///
+    /// Examples of possible value: 80x24, 120x40
+    #[arg(
+        long,
+        value_name = "COLSxROWS",
+        default_value = "10x5",
+        value_parser = parse_term_size,
+        value_hint = ValueHint::Other
+    )]
+    pub term_size: (u32, u32),
+
+    /// Number of times to retry setting up the echo shell
+    ///
+    /// Only the channel, pseudo-terminal and shell setup is retried,
+    /// never the measurement itself
+    #[arg(long, value_name = "COUNT", default_value_t = 2, value_hint = ValueHint::Other)]
+    pub echo_setup_retries: usize,
+
+    /// Number of times to retry opening a channel refused by the server
+    ///
+    /// Servers refuse channels beyond their MaxSessions or MaxStartups limits,
+    /// which is common on busy bastions. Retries back off exponentially
+    #[arg(long, value_name = "COUNT", default_value_t = 0, value_hint = ValueHint::Other)]
+    pub channel_retries: usize,
+
+    /// Number of times to retry an SFTP call that failed transiently
+    ///
+    /// Covers opening, creating and reading metadata of the remote file as well
+    /// as each read and write, with backoff. Only timeouts are retried, errors
+    /// like a missing file, denied permission or a full disk fail right away
+    #[arg(long, value_name = "COUNT", default_value_t = 0, value_hint = ValueHint::Other)]
+    pub sftp_retries: usize,
+
+    /// Do not filter out shell output other than the echoed characters
+    ///
+    /// By default, prompts, escape sequences and other noise from richly
+    /// configured shells are skipped until the sent character is echoed,
+    /// and bracketed paste and line editing are turned off in the shell
+    ///
+    /// Useful for debugging echo test behavior
+    #[arg(long)]
+    pub no_echo_filter: bool,
+
+    /// Echo randomly chosen characters instead of cycling the alphabet
+    ///
+    /// Keeps SSH compression from shrinking the predictable payload and
+    /// understating the latency. Always on when compression is negotiated
+    #[arg(long)]
+    pub random_echo_payload: bool,
+
+    /// Report these PERCENTILES of echo latency
+    ///
+    /// Comma separated list of percentiles between 0 and 100,
+    /// fractional values are interpolated between samples
+    ///
+    /// Examples of possible value: 50,90,99,99.9
+    #[arg(
+        short = 'P',
+        long,
+        value_name = "PERCENTILES",
+        value_delimiter = ',',
+        value_parser = parse_percentile,
+        value_hint = ValueHint::Other
+    )]
+    pub percentiles: Vec<f64>,
+
+    /// Discard the top and bottom PCT percent of echo latencies
+    ///
+    /// Reports the trimmed average, standard deviation and median
+    /// alongside the untrimmed statistics
+    ///
+    /// Examples of possible value: 1, 5, 2.5
+    #[arg(
+        long,
+        value_name = "PCT",
+        value_parser = parse_trim_percentage,
+        value_hint = ValueHint::Other
+    )]
+    pub trim_outliers: Option<f64>,
+
+    /// Count echo latencies in buckets split at these BOUNDARIES
+    ///
+    /// Reports the number and share of samples in each range
+    ///
+    /// Examples of possible value: 1ms,5ms,10ms,50ms
+    #[arg(
+        long,
+        value_name = "BOUNDARIES",
+        value_delimiter = ',',
+        value_parser = parse_duration,
+        value_hint = ValueHint::Other
+    )]
+    pub latency_buckets: Vec<Duration>,
+
+    /// Write echo latencies to FILE as an HdrHistogram distribution
+    ///
+    /// Uses the percentile distribution text format with values in
+    /// milliseconds, which HdrHistogram plotting tools can load
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub hdr_output: Option<PathBuf>,
+
     /// File SIZE for speed test
     ///
     /// Not recommended to use very small sizes for accurate results
@@ -145,20 +639,221 @@ pub struct Options {
     )]
     pub chunk_size: u64,
 
+    /// Pick the chunk size from the measured round trip time
+    ///
+    /// Sizes chunks after the bandwidth-delay product, using the echo test
+    /// result if it ran or a few quick echoes otherwise
+    #[arg(long, conflicts_with = "chunk_size")]
+    pub auto_chunk_size: bool,
+
+    /// Maximum SIZE of memory to use for the speed test data
+    ///
+    /// The whole file for speed test is generated in memory,
+    /// so --size larger than this is rejected to avoid running out of memory
+    ///
+    /// Examples of possible value: 1.5K(B), 3Mi(B), 0.1Ki(B), 500(B)
+    #[arg(
+        long,
+        default_value = "256MiB",
+        value_parser = parse_file_size,
+        value_hint = ValueHint::Other
+    )]
+    pub max_memory: u64,
+
+    /// Leave the first SIZE of each transfer out of the measured speed
+    ///
+    /// The data is still transferred, but the clock only starts afterwards
+    /// so that the result reflects steady state rather than TCP slow start
+    ///
+    /// Examples of possible value: 1.5K(B), 3Mi(B), 0.1Ki(B), 500(B)
+    #[arg(
+        long,
+        default_value = "0",
+        value_parser = parse_file_size,
+        value_hint = ValueHint::Other
+    )]
+    pub speed_warmup_bytes: u64,
+
+    /// Transfer for a fixed DURATION instead of a fixed size
+    ///
+    /// Each transfer stops once the time is up and reports the speed over
+    /// what got through, which suits links too slow to move a whole file.
+    /// Downloads of the remote file also stop at its end. Requires SFTP
+    ///
+    /// Examples of possible value: 5s, 1m, 500ms
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = parse_duration,
+        value_hint = ValueHint::Other
+    )]
+    pub speed_duration: Option<Duration>,
+
+    /// Maximum total SIZE of data transferred by the speed test
+    ///
+    /// Transfers are shortened to stay within the budget, so results may cover
+    /// less than --size. Useful on metered connections
+    ///
+    /// Examples of possible value: 1.5K(B), 3Mi(B), 0.1Ki(B), 500(B)
+    #[arg(long, value_parser = parse_file_size, value_hint = ValueHint::Other)]
+    pub data_budget: Option<u64>,
+
     /// Remote FILE path for speed tests
     ///
     /// The file will be created on the remote server for the speed test
     ///
-    /// NOTE: This file will not be deleted after the test,
+    /// Defaults to a unique /tmp/sshping-<PID>-<RANDOM>.tmp per run,
+    /// which is deleted after the test
+    ///
+    /// NOTE: An explicitly given file will not be deleted after the test,
     /// so it is recommended to be in /tmp
+    ///
+    /// Tokens %h (host), %u (user), %p (port) and %% are expanded
+    #[arg(short = 'z', long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub remote_file: Option<PathBuf>,
+
+    /// Skip the upload test if the remote file already has the right size
+    ///
+    /// Lets repeated runs with the same --remote-file only benchmark the
+    /// download, the file is uploaded again if its size does not match
+    #[arg(long, requires = "remote_file")]
+    pub reuse_remote_file: bool,
+
+    /// Report these PERCENTILES of throughput over sampling intervals
+    ///
+    /// Shows how stable the throughput is within a single transfer
+    ///
+    /// Examples of possible value: 10,50,90
     #[arg(
-        short = 'z',
         long,
-        value_name = "FILE",
-        default_value = "/tmp/sshping-test.tmp",
-        value_hint = ValueHint::FilePath
+        value_name = "PERCENTILES",
+        value_delimiter = ',',
+        value_parser = parse_percentile,
+        value_hint = ValueHint::Other
+    )]
+    pub throughput_percentiles: Vec<f64>,
+
+    /// Length of throughput sampling intervals in seconds
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 0.1,
+        value_parser = parse_seconds,
+        value_hint = ValueHint::Other
     )]
-    pub remote_file: PathBuf,
+    pub throughput_interval: f64,
+
+    /// File transfer PROTOCOL for speed test
+    ///
+    /// Scp: the classic scp protocol over an exec channel
+    ///
+    /// Sftp: the SFTP subsystem, for servers that disable scp
+    #[arg(
+        long,
+        value_enum,
+        value_name = "PROTOCOL",
+        default_value_t = Protocol::Scp,
+        value_hint = ValueHint::Other
+    )]
+    pub protocol: Protocol,
+
+    /// Sync the uploaded file to the remote disk before stopping the clock
+    ///
+    /// Measures durable write speed rather than writes into the page cache.
+    /// Requires --protocol sftp and fsync@openssh.com support on the server
+    #[arg(long)]
+    pub fsync: bool,
+
+    /// Set the size of the remote file before uploading to it
+    ///
+    /// Filesystems handle the pre-sized, possibly sparse file differently
+    /// from one that grows with each write, so compare both to see how
+    /// the remote disk allocates space. Requires --protocol sftp and a fixed
+    /// file size, so it cannot be used with --speed-duration
+    #[arg(
+        long,
+        overrides_with = "no_preallocate",
+        conflicts_with = "speed_duration"
+    )]
+    pub preallocate: bool,
+
+    /// Let the remote file grow with each write, the default
+    #[arg(long, overrides_with = "preallocate")]
+    pub no_preallocate: bool,
+
+    /// Also show the estimated throughput on the wire and protocol overhead
+    ///
+    /// Estimated from SSH packet framing, SFTP requests and TCP/IP headers,
+    /// to compare the speed against the link rate
+    #[arg(long)]
+    pub show_overhead: bool,
+
+    /// Download SOURCE for speed test
+    ///
+    /// File: download the remote file written by the upload test
+    ///
+    /// Urandom, Zero: stream data generated on the fly from /dev/urandom or
+    /// /dev/zero on the remote, independent of the uploaded file
+    #[arg(
+        long,
+        value_enum,
+        value_name = "SOURCE",
+        default_value_t = DownloadSource::File,
+        value_hint = ValueHint::Other
+    )]
+    pub download_source: DownloadSource,
+
+    /// Measure echo latency under load during the speed test
+    ///
+    /// Echo probes using the echo command are sent in between chunks
+    /// while transferring, reporting the median latency for each direction
+    ///
+    /// Compare with the idle latency from echo test to spot bufferbloat
+    #[arg(short = 'L', long)]
+    pub loaded_latency: bool,
+
+    /// Diagnose whether the speed test is bound by remote disk or network
+    ///
+    /// Additionally transfers the same amount of data from /dev/zero and to
+    /// /dev/null on the remote, and flags the file-backed result if it is
+    /// significantly lower than the in-memory one
+    #[arg(long)]
+    pub diagnose_bottleneck: bool,
+
+    /// Warn if upload and download speeds differ by more than RATIO
+    ///
+    /// A large gap between the directions can point to traffic shaping,
+    /// a misconfigured link or a half-duplex problem
+    #[arg(
+        long,
+        value_name = "RATIO",
+        default_value_t = 3.0,
+        value_parser = parse_ratio,
+        value_hint = ValueHint::Other
+    )]
+    pub asymmetry_warn: f64,
+
+    /// Print a single line from TEMPLATE instead of the table
+    ///
+    /// Placeholders: {host}, {user}, {port}, {connect_time}, {auth_method},
+    /// {chars_sent}, {latency_avg}, {latency_std}, {latency_med},
+    /// {latency_min}, {latency_max}, {upload_size}, {upload_time},
+    /// {upload_speed}, {download_size}, {download_time}, {download_speed},
+    /// {asymmetry_ratio}
+    ///
+    /// Use {{ and }} for literal braces
+    ///
+    /// Examples of possible value: "{host} up={upload_speed} lat={latency_avg}"
+    #[arg(long, value_name = "TEMPLATE", value_hint = ValueHint::Other)]
+    pub template: Option<String>,
+
+    /// Write results to the stdin of COMMAND instead of stdout
+    ///
+    /// The command is run by the shell and waited for before exiting
+    ///
+    /// Examples of possible value: "jq -R .", "tee -a results.log"
+    #[arg(long, value_name = "COMMAND", value_hint = ValueHint::CommandString)]
+    pub pipe_to: Option<String>,
 
     /// Table style for output
     ///
@@ -192,10 +887,118 @@ pub struct Options {
     #[arg(short = 'H', long)]
     pub human_readable: bool,
 
+    /// Number of decimal places in human-friendly sizes and speeds
+    ///
+    /// Keeps the number of digits consistent across rows and runs
+    ///
+    /// This option is only used in human readable mode
+    #[arg(long, value_name = "N", value_hint = ValueHint::Other)]
+    pub output_precision: Option<usize>,
+
+    /// Append the exact byte count to human-friendly sizes
+    ///
+    /// Tells 8.0 MB of 8000000 bytes apart from 8388608 bytes, e.g.
+    /// 8.0 MB (8000000 bytes)
+    ///
+    /// This option is only used in human readable mode
+    #[arg(long)]
+    pub show_exact_bytes: bool,
+
+    /// Style of durations in output
+    ///
+    /// Full: all units of human-friendly durations, e.g. 1ms 42us 100ns
+    ///
+    /// Compact: two largest units of human-friendly durations, e.g. 1ms 42us
+    ///
+    /// Seconds: decimal number of seconds regardless of --human-readable,
+    /// e.g. 0.0010421s
+    #[arg(
+        long,
+        value_enum,
+        value_name = "STYLE",
+        default_value_t = DurationStyle::Compact,
+        value_hint = ValueHint::Other
+    )]
+    pub duration_style: DurationStyle,
+
+    /// Show a timeline of each phase in STYLE after the results
+    ///
+    /// Ascii: horizontal bars drawn with block characters
+    ///
+    /// Svg: a simple scalable graphic
+    #[arg(long, value_enum, value_name = "STYLE", value_hint = ValueHint::Other)]
+    pub timeline: Option<TimelineStyle>,
+
+    /// Write the timeline to FILE instead of stdout
+    #[arg(
+        long,
+        value_name = "FILE",
+        requires = "timeline",
+        value_hint = ValueHint::FilePath
+    )]
+    pub timeline_output: Option<PathBuf>,
+
+    /// Show when each phase started and ended
+    ///
+    /// Timestamps are wall clock times in RFC 3339 format and UTC,
+    /// for lining results up with other monitoring data
+    #[arg(long)]
+    pub timestamps: bool,
+
+    /// Also send results to syslog, at HOST:PORT over UDP if given
+    ///
+    /// Without a value, results go to the local syslog daemon
+    ///
+    /// Examples of possible value: logs.example.com:514
+    #[arg(
+        long,
+        value_name = "HOST:PORT",
+        num_args = 0..=1,
+        value_hint = ValueHint::Hostname
+    )]
+    pub syslog: Option<Option<String>>,
+
+    /// Only send results to syslog, without printing the table
+    #[arg(long, requires = "syslog")]
+    pub syslog_only: bool,
+
+    /// Do not show progress bars, same as --progress-style none
+    ///
+    /// Progress bars are also hidden when stderr is not a terminal
+    #[arg(long)]
+    pub no_progress: bool,
+
+    /// Show progress in STYLE
+    ///
+    /// Bar: a full progress bar for each phase
+    ///
+    /// Line: a single status line summarizing all phases so far,
+    /// gentler on slow terminals
+    #[arg(
+        long,
+        value_enum,
+        value_name = "STYLE",
+        default_value_t = ProgressDisplay::Bar,
+        value_hint = ValueHint::Other
+    )]
+    pub progress_style: ProgressDisplay,
+
     /// Wait for keyboard input before exiting
+    ///
+    /// Skipped when stdin is not a terminal
     #[arg(short, long)]
     pub key_wait: bool,
 
+    /// Time limit for waiting keyboard input in seconds
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 60.0,
+        value_parser = parse_seconds,
+        value_hint = ValueHint::Other
+    )]
+    pub key_wait_timeout: f64,
+
     /// Show verbose output, use multiple for more noise
     ///
     /// -v: Show warnings
@@ -228,11 +1031,80 @@ pub enum Test {
     Both,
 }
 
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DownloadSource {
+    /// Remote file from upload test
+    File,
+    /// Random data from /dev/urandom
+    Urandom,
+    /// Zeros from /dev/zero
+    Zero,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AuthMethod {
+    /// Keys from the agent, then the identity file
+    Publickey,
+    /// Prompts answered with the password
+    KeyboardInteractive,
+    /// Password
+    Password,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Protocol {
+    /// Secure copy
+    Scp,
+    /// SSH file transfer protocol
+    Sftp,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DurationStyle {
+    /// Show all units
+    Full,
+    /// Show the two largest units
+    Compact,
+    /// Show decimal seconds
+    Seconds,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Preset {
+    /// AEAD and CTR ciphers, SHA-2 MACs, curve25519/ECDH kex, Ed25519/ECDSA/RSA-SHA2 keys
+    Modern,
+    /// Modern plus CBC ciphers, SHA-1 MACs, group1/group14 kex, ssh-rsa/ssh-dss keys
+    Compat,
+    /// Only FIPS 140 approved AES, SHA-2, NIST ECDH/DH and ECDSA/RSA-SHA2
+    Fips,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ProgressDisplay {
+    /// Progress bar per phase
+    Bar,
+    /// Single status line across phases
+    Line,
+    /// No progress at all
+    None,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TimelineStyle {
+    /// Block characters in terminal
+    Ascii,
+    /// Scalable vector graphic
+    Svg,
+}
+
 #[derive(Clone, Debug)]
 pub struct Target {
     pub user: String,
     pub host: String,
     pub port: u16,
+    // Whether the user and port were given rather than defaulted
+    pub user_given: bool,
+    pub port_given: bool,
 }
 
 fn parse_target(s: &str) -> Result<Target, String> {
@@ -247,29 +1119,170 @@ fn parse_target(s: &str) -> Result<Target, String> {
             return Err("Invalid target format. Must be [user@]host[:port]".to_string());
         }
     };
-    let mut parts = parts.next().unwrap().split(':');
-    let host = parts.next().unwrap().to_string();
-    let port = match parts.clone().count() {
+    let address = parts.next().unwrap();
+    // IPv6 addresses, possibly with a zone like fe80::1%eth0, are given in
+    // brackets when followed by a port
+    let (host, port) = if let Some(rest) = address.strip_prefix('[') {
+        let (host, rest) = rest
+            .split_once(']')
+            .ok_or_else(|| "Invalid target format. Missing ] after IPv6 address".to_string())?;
+        match rest {
+            "" => (host, None),
+            _ => match rest.strip_prefix(':') {
+                Some(port) => (host, Some(port)),
+                None => {
+                    return Err("Invalid target format. Must be [user@]host[:port]".to_string());
+                }
+            },
+        }
+    } else if address.matches(':').count() > 1 {
+        (address, None)
+    } else {
+        match address.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (address, None),
+        }
+    };
+    let host = host.to_string();
+    let port_given = port.is_some();
+    let port = match port {
         // Use default port 22 if not specified
-        0 => 22,
+        None => 22,
         // Or use the specified port
-        1 => parts.next().unwrap().parse().unwrap(),
-        // Throw an error if : present more than once
-        _ => {
-            return Err("Invalid target format. Must be [user@]host[:port]".to_string());
-        }
+        Some(port) => port
+            .parse()
+            .map_err(|_| format!("Invalid port {port:?}. Must be a number from 0 to 65535"))?,
+    };
+    Ok(Target {
+        user,
+        host,
+        port,
+        user_given: s.contains('@'),
+        port_given,
+    })
+}
+
+#[derive(Clone, Debug)]
+pub struct HttpProxy {
+    pub host: String,
+    pub port: u16,
+    // USER:PASSWORD for Basic authentication
+    pub credentials: Option<String>,
+}
+
+impl HttpProxy {
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+fn parse_http_proxy(s: &str) -> Result<HttpProxy, String> {
+    // The password may contain @, the host may not
+    let (credentials, address) = match s.rsplit_once('@') {
+        Some((credentials, address)) => (Some(credentials.to_string()), address),
+        None => (None, s),
     };
-    Ok(Target { user, host, port })
+    let invalid = || format!("Invalid proxy {s:?}. Must be [USER:PASSWORD@]HOST:PORT");
+    let (host, port) = address.rsplit_once(':').ok_or_else(invalid)?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok(HttpProxy {
+        host: host.to_string(),
+        port: port.parse().map_err(|_| invalid())?,
+        credentials,
+    })
+}
+
+fn parse_resolver(s: &str) -> Result<SocketAddr, String> {
+    // Use the standard DNS port if not specified
+    s.parse::<SocketAddr>()
+        .or_else(|_| s.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+        .map_err(|_| format!("Invalid resolver {s:?}. Must be IP[:PORT]"))
+}
+
+fn parse_env_var(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .filter(|(key, _)| !key.is_empty())
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("Invalid environment variable {s:?}. Must be KEY=VALUE"))
+}
+
+fn parse_term_size(s: &str) -> Result<(u32, u32), String> {
+    s.split_once(['x', 'X'])
+        .and_then(|(cols, rows)| Some((cols.parse().ok()?, rows.parse().ok()?)))
+        .filter(|&(cols, rows)| cols > 0 && rows > 0)
+        .ok_or_else(|| format!("Invalid terminal size {s:?}. Must be COLSxROWS, e.g. 80x24"))
 }
 
 fn parse_local_path(s: &str) -> Result<PathBuf, String> {
-    Ok(PathBuf::from(tilde(s).to_string())
+    PathBuf::from(tilde(s).to_string())
         .canonicalize()
-        .expect("Failed to parse path"))
+        .map_err(|e| format!("Failed to parse path {s:?}: {e}"))
+}
+
+fn parse_ssh_option(s: &str) -> Result<String, String> {
+    // Accept both KEY=VALUE and KEY VALUE forms like ssh does
+    let (key, value) = s
+        .trim()
+        .split_once(|c: char| c == '=' || c.is_whitespace())
+        .ok_or_else(|| "Invalid ssh option format. Must be KEY=VALUE".to_string())?;
+    let (key, value) = (key.trim(), value.trim());
+    if key.is_empty() || value.is_empty() {
+        return Err("Invalid ssh option format. Must be KEY=VALUE".to_string());
+    }
+    Ok(format!("{key} {value}"))
+}
+
+fn parse_percentile(s: &str) -> Result<f64, String> {
+    match s.trim().parse::<f64>() {
+        Ok(p) if (0.0..=100.0).contains(&p) => Ok(p),
+        _ => Err(format!(
+            "Invalid percentile {s:?}. Must be between 0 and 100"
+        )),
+    }
+}
+
+fn parse_trim_percentage(s: &str) -> Result<f64, String> {
+    match s.trim().parse::<f64>() {
+        Ok(p) if (0.0..50.0).contains(&p) => Ok(p),
+        _ => Err(format!(
+            "Invalid percentage {s:?}. Must be at least 0 and below 50"
+        )),
+    }
+}
+
+fn parse_ratio(s: &str) -> Result<f64, String> {
+    match s.trim().trim_end_matches('x').parse::<f64>() {
+        Ok(r) if r >= 1.0 => Ok(r),
+        _ => Err(format!("Invalid ratio {s:?}. Must be at least 1")),
+    }
+}
+
+fn parse_seconds(s: &str) -> Result<f64, String> {
+    match s.trim().parse::<f64>() {
+        Ok(seconds) if seconds.is_finite() && seconds > 0.0 => Ok(seconds),
+        _ => Err(format!("Invalid time {s:?}. Must be more than 0 seconds")),
+    }
+}
+
+fn parse_wait_seconds(s: &str) -> Result<f64, String> {
+    match s.trim().parse::<f64>() {
+        Ok(seconds) if seconds.is_finite() && seconds >= 0.0 => Ok(seconds),
+        _ => Err(format!("Invalid time {s:?}. Must be at least 0 seconds")),
+    }
+}
+
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    humantime::parse_duration(s.trim()).map_err(|e| format!("Invalid duration {s:?}: {e}"))
 }
 
 fn parse_file_size(s: &str) -> Result<u64, String> {
-    let size = s.parse::<ByteSize>().unwrap().0;
+    let size = s
+        .parse::<ByteSize>()
+        .map_err(|e| format!("Invalid size {s:?}: {e}"))?
+        .0;
     Ok(size)
 }
 
@@ -283,3 +1296,113 @@ fn get_styles() -> Styles {
         .valid(AnsiColor::Green.on_default().bold())
         .invalid(AnsiColor::Yellow.on_default().bold())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn target_defaults_user_and_port() {
+        let target = parse_target("example.com").unwrap();
+        assert_eq!(target.host, "example.com");
+        assert_eq!(target.user, username());
+        assert_eq!(target.port, 22);
+        assert!(!target.user_given);
+        assert!(!target.port_given);
+    }
+
+    #[test]
+    fn target_with_user_and_port() {
+        let target = parse_target("alice@example.com:2222").unwrap();
+        assert_eq!(target.user, "alice");
+        assert_eq!(target.host, "example.com");
+        assert_eq!(target.port, 2222);
+        assert!(target.user_given);
+        assert!(target.port_given);
+    }
+
+    #[test]
+    fn target_ipv6() {
+        let target = parse_target("fe80::1").unwrap();
+        assert_eq!(target.host, "fe80::1");
+        assert_eq!(target.port, 22);
+
+        let target = parse_target("root@[2001:db8::1]:2222").unwrap();
+        assert_eq!(target.user, "root");
+        assert_eq!(target.host, "2001:db8::1");
+        assert_eq!(target.port, 2222);
+
+        let target = parse_target("[::1]").unwrap();
+        assert_eq!(target.host, "::1");
+        assert_eq!(target.port, 22);
+    }
+
+    #[test]
+    fn target_ipv6_zone() {
+        let target = parse_target("[fe80::1%eth0]:22").unwrap();
+        assert_eq!(target.host, "fe80::1%eth0");
+        assert_eq!(target.port, 22);
+    }
+
+    #[test]
+    fn target_errors() {
+        assert!(parse_target("a@b@example.com").is_err());
+        assert!(parse_target("example.com:ssh").is_err());
+        assert!(parse_target("example.com:65536").is_err());
+        assert!(parse_target("[::1").is_err());
+        assert!(parse_target("[::1]22").is_err());
+    }
+
+    #[test]
+    fn file_size() {
+        assert_eq!(parse_file_size("500").unwrap(), 500);
+        assert_eq!(parse_file_size("1.5K").unwrap(), 1500);
+        assert_eq!(parse_file_size("8MB").unwrap(), 8_000_000);
+        assert_eq!(parse_file_size("3Mi").unwrap(), 3 * 1024 * 1024);
+        assert_eq!(parse_file_size("0.1KiB").unwrap(), 102);
+    }
+
+    #[test]
+    fn file_size_errors() {
+        assert!(parse_file_size("").is_err());
+        assert!(parse_file_size("big").is_err());
+        assert!(parse_file_size("-1K").is_err());
+        assert!(parse_file_size("1Q").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fd_rejects_standard_streams_and_conflicts() {
+        let parse = |args: &[&str]| {
+            let mut argv = vec!["sshping", "--config", env!("CARGO_MANIFEST_DIR")];
+            argv.extend_from_slice(args);
+            argv.push("example.com");
+            Options::try_parse_from(argv)
+        };
+        assert_eq!(parse(&["--fd", "3"]).unwrap().fd, Some(3));
+        for fd in ["0", "1", "2", "-1"] {
+            assert!(parse(&["--fd", fd]).is_err());
+        }
+        assert!(parse(&["--fd", "3", "--resolver", "10.0.0.53"]).is_err());
+        assert!(parse(&["--fd", "3", "--https-proxy", "proxy:3128"]).is_err());
+        #[cfg(target_os = "linux")]
+        assert!(parse(&["--fd", "3", "--interface", "eth0"]).is_err());
+    }
+
+    #[test]
+    fn wait_seconds() {
+        assert_eq!(parse_wait_seconds("0"), Ok(0.0));
+        assert_eq!(parse_wait_seconds("2.5"), Ok(2.5));
+        for invalid in ["-1", "NaN", "inf", "soon"] {
+            assert!(parse_wait_seconds(invalid).is_err());
+        }
+    }
+
+    #[test]
+    fn seconds() {
+        assert_eq!(parse_seconds("0.5"), Ok(0.5));
+        for invalid in ["0", "-1", "NaN", "inf", "soon"] {
+            assert!(parse_seconds(invalid).is_err());
+        }
+    }
+}
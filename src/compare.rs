@@ -0,0 +1,46 @@
+use log::{info, warn};
+
+use crate::{
+    cli::{Options, Test},
+    config::SessionPreferences,
+    connect::connect_session,
+    summary::TargetSummary,
+    tests::{remove_remote_file, run_echo_test, run_speed_test},
+    timeline::Timeline,
+    util::Formatter,
+};
+
+// Connect to the target of the options and run the selected tests
+pub fn run_target(
+    opts: &Options,
+    preferences: &SessionPreferences,
+    cleanup: bool,
+    formatter: &Formatter,
+) -> Result<TargetSummary, String> {
+    info!("Testing {}", opts.target.host);
+    let (session, connect_time, _) = connect_session(opts, preferences, &mut Timeline::start())?;
+    let echo = if opts.run_tests == Test::Echo || opts.run_tests == Test::Both {
+        Some(run_echo_test(&session, opts, formatter)?)
+    } else {
+        None
+    };
+    let speed = if opts.run_tests == Test::Speed || opts.run_tests == Test::Both {
+        let result = run_speed_test(&session, opts, formatter);
+        if cleanup {
+            if let Some(remote_file) = &opts.remote_file {
+                if let Err(e) = remove_remote_file(&session, remote_file) {
+                    warn!("Failed to remove remote file {remote_file:?}: {e}");
+                }
+            }
+        }
+        Some(result?)
+    } else {
+        None
+    };
+    let _ = session.disconnect(None, "sshping compare", None);
+    Ok(TargetSummary {
+        connect_time,
+        echo,
+        speed,
+    })
+}
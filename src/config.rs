@@ -0,0 +1,585 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    io::BufRead,
+    net::SocketAddr,
+};
+
+use log::{debug, warn};
+use ssh2::{MethodType, Session};
+use ssh2_config::HostParams;
+
+use crate::cli::{Options, Preset};
+
+// Ciphers of the modern preset
+const MODERN_CIPHERS: &str = "chacha20-poly1305@openssh.com,aes256-gcm@openssh.com,\
+    aes128-gcm@openssh.com,aes256-ctr,aes192-ctr,aes128-ctr";
+// MACs of the modern preset
+const MODERN_MACS: &str = "hmac-sha2-512-etm@openssh.com,hmac-sha2-256-etm@openssh.com,\
+    hmac-sha2-512,hmac-sha2-256";
+// Key exchange algorithms of the modern preset
+const MODERN_KEX: &str = "curve25519-sha256,curve25519-sha256@libssh.org,\
+    ecdh-sha2-nistp521,ecdh-sha2-nistp384,ecdh-sha2-nistp256,\
+    diffie-hellman-group18-sha512,diffie-hellman-group16-sha512";
+// Host key algorithms of the modern preset
+const MODERN_HOST_KEYS: &str = "ssh-ed25519,ecdsa-sha2-nistp521,ecdsa-sha2-nistp384,\
+    ecdsa-sha2-nistp256,rsa-sha2-512,rsa-sha2-256";
+
+// Legacy ciphers added by the compat preset
+const LEGACY_CIPHERS: &str = "aes256-cbc,aes192-cbc,aes128-cbc,3des-cbc";
+// Legacy MACs added by the compat preset
+const LEGACY_MACS: &str = "hmac-sha1,hmac-sha1-96";
+// Legacy key exchange algorithms added by the compat preset
+const LEGACY_KEX: &str = "diffie-hellman-group-exchange-sha256,diffie-hellman-group14-sha256,\
+    diffie-hellman-group14-sha1,diffie-hellman-group-exchange-sha1,diffie-hellman-group1-sha1";
+// Legacy host key algorithms added by the compat preset
+const LEGACY_HOST_KEYS: &str = "ssh-rsa,ssh-dss";
+
+// Ciphers of the fips preset
+const FIPS_CIPHERS: &str = "aes256-gcm@openssh.com,aes128-gcm@openssh.com,\
+    aes256-ctr,aes192-ctr,aes128-ctr";
+// MACs of the fips preset
+const FIPS_MACS: &str = "hmac-sha2-512,hmac-sha2-256";
+// Key exchange algorithms of the fips preset
+const FIPS_KEX: &str = "ecdh-sha2-nistp521,ecdh-sha2-nistp384,ecdh-sha2-nistp256,\
+    diffie-hellman-group16-sha512,diffie-hellman-group14-sha256";
+// Host key algorithms of the fips preset
+const FIPS_HOST_KEYS: &str = "ecdsa-sha2-nistp521,ecdsa-sha2-nistp384,ecdsa-sha2-nistp256,\
+    rsa-sha2-512,rsa-sha2-256";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressFamily {
+    Inet,
+    Inet6,
+}
+
+impl AddressFamily {
+    pub fn matches(self, addr: &SocketAddr) -> bool {
+        match self {
+            Self::Inet => addr.is_ipv4(),
+            Self::Inet6 => addr.is_ipv6(),
+        }
+    }
+}
+
+// Session settings that only come from ssh config
+#[derive(Debug, Default, Clone)]
+pub struct SessionPreferences {
+    pub connection_attempts: Option<usize>,
+    pub address_family: Option<AddressFamily>,
+    pub compression: Option<bool>,
+    pub ciphers: Option<String>,
+    pub macs: Option<String>,
+    pub kex_algorithms: Option<String>,
+    pub host_key_algorithms: Option<String>,
+}
+
+impl SessionPreferences {
+    // Algorithm lists of the preset, later overridden only by the algorithm
+    // directives the ssh config actually sets
+    pub fn from_preset(preset: Preset) -> Self {
+        let (ciphers, macs, kex_algorithms, host_key_algorithms) = match preset {
+            Preset::Modern => (
+                MODERN_CIPHERS.to_string(),
+                MODERN_MACS.to_string(),
+                MODERN_KEX.to_string(),
+                MODERN_HOST_KEYS.to_string(),
+            ),
+            Preset::Compat => (
+                format!("{MODERN_CIPHERS},{LEGACY_CIPHERS}"),
+                format!("{MODERN_MACS},{LEGACY_MACS}"),
+                format!("{MODERN_KEX},{LEGACY_KEX}"),
+                format!("{MODERN_HOST_KEYS},{LEGACY_HOST_KEYS}"),
+            ),
+            Preset::Fips => (
+                FIPS_CIPHERS.to_string(),
+                FIPS_MACS.to_string(),
+                FIPS_KEX.to_string(),
+                FIPS_HOST_KEYS.to_string(),
+            ),
+        };
+        Self {
+            connection_attempts: None,
+            address_family: None,
+            compression: None,
+            ciphers: Some(ciphers),
+            macs: Some(macs),
+            kex_algorithms: Some(kex_algorithms),
+            host_key_algorithms: Some(host_key_algorithms),
+        }
+    }
+
+    // Apply preferences to the session, must be called before handshake
+    pub fn apply(&self, session: &Session) {
+        if let Some(compression) = self.compression {
+            debug!("Compression: {compression}");
+            session.set_compress(compression);
+        }
+        let methods = [
+            ("cipher", MethodType::CryptCs, &self.ciphers),
+            ("cipher", MethodType::CryptSc, &self.ciphers),
+            ("MAC", MethodType::MacCs, &self.macs),
+            ("MAC", MethodType::MacSc, &self.macs),
+            ("key exchange", MethodType::Kex, &self.kex_algorithms),
+            ("host key", MethodType::HostKey, &self.host_key_algorithms),
+        ];
+        for (name, method_type, prefs) in methods {
+            if let Some(prefs) = prefs {
+                if let Err(e) = session.method_pref(method_type, prefs) {
+                    warn!("Failed to set preferred {name} algorithms {prefs:?}: {e}");
+                }
+            }
+        }
+    }
+}
+
+// Algorithm list of a directive, if the config sets it at all
+fn join_algorithms(algorithms: &Option<Vec<String>>) -> Option<String> {
+    algorithms
+        .as_ref()
+        .filter(|algorithms| !algorithms.is_empty())
+        .map(|algorithms| algorithms.join(","))
+}
+
+// Directives set for the host that sshping does not apply
+pub fn ignored_directives(params: &HostParams) -> Vec<String> {
+    let parsed = [
+        ("BindAddress", params.bind_address.is_some()),
+        ("BindInterface", params.bind_interface.is_some()),
+        (
+            "CASignatureAlgorithms",
+            params.ca_signature_algorithms.is_some(),
+        ),
+        ("CertificateFile", params.certificate_file.is_some()),
+        ("ConnectTimeout", params.connect_timeout.is_some()),
+        (
+            "PubkeyAcceptedAlgorithms",
+            params.pubkey_accepted_algorithms.is_some(),
+        ),
+        (
+            "PubkeyAuthentication",
+            params.pubkey_authentication.is_some(),
+        ),
+        ("RemoteForward", params.remote_forward.is_some()),
+        (
+            "ServerAliveInterval",
+            params.server_alive_interval.is_some(),
+        ),
+        ("TCPKeepAlive", params.tcp_keep_alive.is_some()),
+    ];
+    let raw = params
+        .unsupported_fields
+        .keys()
+        .chain(params.ignored_fields.keys())
+        // Looked up among the raw fields by apply_host_params
+        .filter(|key| {
+            !key.eq_ignore_ascii_case("AddressFamily") && !key.eq_ignore_ascii_case("SetEnv")
+        })
+        .cloned();
+    let mut directives = parsed
+        .into_iter()
+        .filter(|(_, present)| *present)
+        .map(|(name, _)| name.to_string())
+        .chain(raw)
+        .collect::<Vec<_>>();
+    directives.sort_by_key(|directive| directive.to_ascii_lowercase());
+    directives.dedup_by(|a, b| a.eq_ignore_ascii_case(b));
+    directives
+}
+
+pub fn apply_host_params(
+    opts: &mut Options,
+    preferences: &mut SessionPreferences,
+    params: HostParams,
+) {
+    if let Some(host) = params.host_name {
+        opts.target.host = host;
+    }
+    if let Some(user) = params.user {
+        opts.target.user = user;
+    }
+    if let Some(port) = params.port {
+        opts.target.port = port;
+    }
+    if let Some(identity) = params.identity_file {
+        opts.identity = Some(identity[0].to_owned());
+    }
+    if let Some(connection_attempts) = params.connection_attempts {
+        preferences.connection_attempts = Some(connection_attempts);
+    }
+    // Not modelled by ssh2-config, so look it up among the raw fields
+    let address_family = params
+        .unsupported_fields
+        .iter()
+        .chain(params.ignored_fields.iter())
+        .find(|(key, _)| key.eq_ignore_ascii_case("AddressFamily"))
+        .and_then(|(_, values)| values.first());
+    if let Some(address_family) = address_family {
+        preferences.address_family = match address_family.to_ascii_lowercase().as_str() {
+            "inet" => Some(AddressFamily::Inet),
+            "inet6" => Some(AddressFamily::Inet6),
+            "any" => None,
+            _ => {
+                warn!("Ignoring unknown AddressFamily {address_family:?}");
+                preferences.address_family
+            }
+        };
+    }
+    // Variables given on the command line take precedence
+    let set_env = params
+        .unsupported_fields
+        .iter()
+        .chain(params.ignored_fields.iter())
+        .filter(|(key, _)| key.eq_ignore_ascii_case("SetEnv"))
+        .flat_map(|(_, values)| values);
+    for variable in set_env {
+        match variable.split_once('=') {
+            Some((key, value)) => {
+                if !opts.setenv.iter().any(|(set, _)| set == key) {
+                    opts.setenv.push((key.to_string(), value.to_string()));
+                }
+            }
+            None => warn!("Ignoring SetEnv {variable:?} without a value"),
+        }
+    }
+    if let Some(compression) = params.compression {
+        preferences.compression = Some(compression);
+    }
+    if let Some(ciphers) = join_algorithms(&params.ciphers) {
+        preferences.ciphers = Some(ciphers);
+    }
+    if let Some(macs) = join_algorithms(&params.mac) {
+        preferences.macs = Some(macs);
+    }
+    if let Some(kex_algorithms) = join_algorithms(&params.kex_algorithms) {
+        preferences.kex_algorithms = Some(kex_algorithms);
+    }
+    if let Some(host_key_algorithms) = join_algorithms(&params.host_key_algorithms) {
+        preferences.host_key_algorithms = Some(host_key_algorithms);
+    }
+}
+
+// Where a resolved setting of the target came from
+#[derive(Debug, Clone)]
+pub enum Origin {
+    Default,
+    CommandLine,
+    // Line and Host patterns of the block in the ssh config file, if found
+    ConfigFile(Option<(usize, String)>),
+    SshOption,
+}
+
+impl Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Default => write!(f, "default"),
+            Self::CommandLine => write!(f, "command line"),
+            Self::ConfigFile(Some((line, patterns))) => {
+                write!(f, "ssh config line {line} (Host {patterns})")
+            }
+            Self::ConfigFile(None) => write!(f, "ssh config"),
+            Self::SshOption => write!(f, "--ssh-option"),
+        }
+    }
+}
+
+// Origins of the settings shown by --debug-config
+#[derive(Debug, Clone)]
+pub struct ConfigOrigins {
+    pub host: Origin,
+    pub user: Origin,
+    pub port: Origin,
+    pub identity: Origin,
+    pub proxy_jump: Option<(String, Origin)>,
+}
+
+impl ConfigOrigins {
+    pub fn new(opts: &Options) -> Self {
+        let given = |given| {
+            if given {
+                Origin::CommandLine
+            } else {
+                Origin::Default
+            }
+        };
+        Self {
+            host: Origin::CommandLine,
+            user: given(opts.target.user_given),
+            port: given(opts.target.port_given),
+            identity: given(opts.identity.is_some()),
+            proxy_jump: None,
+        }
+    }
+
+    // Note the settings a layer of host params is about to change, with
+    // ORIGIN telling where the directive of a keyword came from
+    pub fn update(&mut self, params: &HostParams, origin: impl Fn(&str) -> Origin) {
+        if params.host_name.is_some() {
+            self.host = origin("hostname");
+        }
+        if params.user.is_some() {
+            self.user = origin("user");
+        }
+        if params.port.is_some() {
+            self.port = origin("port");
+        }
+        if params.identity_file.is_some() {
+            self.identity = origin("identityfile");
+        }
+        // Not modelled by ssh2-config, so look it up among the raw fields
+        let proxy_jump = params
+            .unsupported_fields
+            .iter()
+            .chain(params.ignored_fields.iter())
+            .find(|(key, _)| key.eq_ignore_ascii_case("ProxyJump"));
+        if let Some((_, values)) = proxy_jump {
+            self.proxy_jump = Some((values.join(" "), origin("proxyjump")));
+        }
+    }
+}
+
+// Line and Host patterns of the first directive of each keyword that
+// applies to ALIAS, as the first value found is the one used
+pub fn directive_lines(reader: impl BufRead, alias: &str) -> HashMap<String, (usize, String)> {
+    let mut lines = HashMap::new();
+    // Directives before the first Host line apply to every host
+    let mut block = Some("*".to_string());
+    for (number, line) in reader.lines().map_while(Result::ok).enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (keyword, value) = line
+            .split_once(|c: char| c.is_whitespace() || c == '=')
+            .unwrap_or((line, ""));
+        let value = value.trim_matches(|c: char| c.is_whitespace() || c == '=');
+        match keyword.to_ascii_lowercase().as_str() {
+            "host" => block = host_matches(value, alias).then(|| value.to_string()),
+            // Match blocks are not supported, so never report them
+            "match" => block = None,
+            keyword => {
+                if let Some(patterns) = &block {
+                    lines
+                        .entry(keyword.to_string())
+                        .or_insert_with(|| (number + 1, patterns.clone()));
+                }
+            }
+        }
+    }
+    lines
+}
+
+// Whether ALIAS matches the patterns of a Host line, any matching negated
+// pattern ruling the block out
+fn host_matches(patterns: &str, alias: &str) -> bool {
+    let mut matched = false;
+    for pattern in patterns.split_whitespace() {
+        match pattern.strip_prefix('!') {
+            Some(pattern) if wildcard_matches(pattern.as_bytes(), alias.as_bytes()) => {
+                return false;
+            }
+            Some(_) => {}
+            None => matched |= wildcard_matches(pattern.as_bytes(), alias.as_bytes()),
+        }
+    }
+    matched
+}
+
+// Match TEXT against PATTERN with * and ? wildcards, ignoring case
+fn wildcard_matches(pattern: &[u8], text: &[u8]) -> bool {
+    match (pattern.split_first(), text.split_first()) {
+        (None, _) => text.is_empty(),
+        (Some((b'*', rest)), _) => {
+            wildcard_matches(rest, text)
+                || (!text.is_empty() && wildcard_matches(pattern, &text[1..]))
+        }
+        (Some((b'?', rest)), Some((_, text))) => wildcard_matches(rest, text),
+        (Some((p, rest)), Some((t, text))) => {
+            p.eq_ignore_ascii_case(t) && wildcard_matches(rest, text)
+        }
+        (Some(_), None) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use ssh2_config::{ParseRule, SshConfig};
+
+    use super::*;
+
+    // Options and preferences after applying CONFIG for the host `example`
+    fn apply(config: &str) -> (Options, SessionPreferences) {
+        apply_over(config, SessionPreferences::default())
+    }
+
+    // Like apply, but on top of the given PREFERENCES
+    fn apply_over(
+        config: &str,
+        mut preferences: SessionPreferences,
+    ) -> (Options, SessionPreferences) {
+        let params = SshConfig::default()
+            .parse(
+                &mut config.as_bytes(),
+                ParseRule::ALLOW_UNKNOWN_FIELDS | ParseRule::ALLOW_UNSUPPORTED_FIELDS,
+            )
+            .unwrap()
+            .query("example");
+        let mut opts =
+            Options::parse_from(["sshping", "--config", env!("CARGO_MANIFEST_DIR"), "example"]);
+        apply_host_params(&mut opts, &mut preferences, params);
+        (opts, preferences)
+    }
+
+    #[test]
+    fn alias_block_wins_over_wildcard() {
+        let (opts, _) = apply(
+            "Host 10.0.0.1\n    Port 2200\n\
+             Host example\n    HostName 10.0.0.1\n    User alice\n\
+             Host *\n    HostName wildcard.invalid\n    User bob\n    Port 2222\n",
+        );
+        // Blocks match the alias, not the HostName, and the first value
+        // found is used, the wildcard only filling in the rest
+        assert_eq!(opts.target.host, "10.0.0.1");
+        assert_eq!(opts.target.user, "alice");
+        assert_eq!(opts.target.port, 2222);
+    }
+
+    #[test]
+    fn connection_attempts_and_address_family() {
+        let (_, preferences) =
+            apply("Host example\n    ConnectionAttempts 3\n    AddressFamily inet6\n");
+        assert_eq!(preferences.connection_attempts, Some(3));
+        assert_eq!(preferences.address_family, Some(AddressFamily::Inet6));
+
+        let (_, preferences) = apply("Host example\n    AddressFamily inet\n");
+        assert_eq!(preferences.connection_attempts, None);
+        assert_eq!(preferences.address_family, Some(AddressFamily::Inet));
+    }
+
+    #[test]
+    fn address_family_any_or_unknown() {
+        let (_, preferences) = apply("Host example\n    AddressFamily any\n");
+        assert_eq!(preferences.address_family, None);
+
+        // Unknown values are ignored with a warning
+        let (_, preferences) = apply("Host example\n    AddressFamily ipx\n");
+        assert_eq!(preferences.address_family, None);
+    }
+
+    #[test]
+    fn unset_algorithms_are_left_alone() {
+        let (_, preferences) = apply("Host example\n    User alice\n");
+        assert_eq!(preferences.compression, None);
+        assert_eq!(preferences.ciphers, None);
+        assert_eq!(preferences.macs, None);
+        assert_eq!(preferences.kex_algorithms, None);
+        assert_eq!(preferences.host_key_algorithms, None);
+    }
+
+    #[test]
+    fn set_algorithms_are_applied() {
+        let (_, preferences) = apply(
+            "Host example\n    Ciphers aes128-ctr,aes256-ctr\n    MACs hmac-sha2-256\n\
+             Host *\n    KexAlgorithms curve25519-sha256\n",
+        );
+        assert_eq!(
+            preferences.ciphers.as_deref(),
+            Some("aes128-ctr,aes256-ctr")
+        );
+        assert_eq!(preferences.macs.as_deref(), Some("hmac-sha2-256"));
+        assert_eq!(
+            preferences.kex_algorithms.as_deref(),
+            Some("curve25519-sha256")
+        );
+        assert_eq!(preferences.host_key_algorithms, None);
+    }
+
+    #[test]
+    fn preset_survives_config_without_algorithms() {
+        let preset = SessionPreferences::from_preset(Preset::Fips);
+        let (_, preferences) = apply_over(
+            "Host example\n    User alice\n    Compression yes\n",
+            preset.clone(),
+        );
+        assert_eq!(preferences.ciphers, preset.ciphers);
+        assert_eq!(preferences.macs, preset.macs);
+        assert_eq!(preferences.kex_algorithms, preset.kex_algorithms);
+        assert_eq!(preferences.host_key_algorithms, preset.host_key_algorithms);
+
+        // Only the directives the config sets replace the preset lists
+        let (_, preferences) = apply_over("Host example\n    Ciphers aes128-ctr\n", preset.clone());
+        assert_eq!(preferences.ciphers.as_deref(), Some("aes128-ctr"));
+        assert_eq!(preferences.macs, preset.macs);
+    }
+
+    #[test]
+    fn address_family_not_in_ignored_directives() {
+        let params = SshConfig::default()
+            .parse(
+                &mut "Host example\n    AddressFamily inet\n".as_bytes(),
+                ParseRule::ALLOW_UNKNOWN_FIELDS | ParseRule::ALLOW_UNSUPPORTED_FIELDS,
+            )
+            .unwrap()
+            .query("example");
+        assert!(ignored_directives(&params).is_empty());
+    }
+
+    #[test]
+    fn only_written_algorithm_directives_are_ignored() {
+        let parse = |config: &str| {
+            SshConfig::default()
+                .parse(&mut config.as_bytes(), ParseRule::STRICT)
+                .unwrap()
+                .query("example")
+        };
+        let params = parse("Host example\n    User alice\n    Ciphers aes128-ctr\n");
+        assert!(ignored_directives(&params).is_empty());
+
+        let params = parse("Host example\n    CASignatureAlgorithms ssh-ed25519\n");
+        assert_eq!(ignored_directives(&params), ["CASignatureAlgorithms"]);
+    }
+
+    #[test]
+    fn wildcards_ignore_case() {
+        assert!(wildcard_matches(b"*.Example.COM", b"web.example.com"));
+        assert!(wildcard_matches(b"web?", b"web1"));
+        assert!(!wildcard_matches(b"web?", b"web"));
+        assert!(wildcard_matches(b"*", b""));
+        assert!(!wildcard_matches(b"web", b"web1"));
+    }
+
+    #[test]
+    fn negated_host_patterns() {
+        assert!(host_matches(
+            "*.example.com !db.example.com",
+            "web.example.com"
+        ));
+        assert!(!host_matches(
+            "*.example.com !db.example.com",
+            "db.example.com"
+        ));
+        // A negated pattern alone never matches
+        assert!(!host_matches("!db", "web"));
+    }
+
+    #[test]
+    fn first_directive_of_matching_blocks_wins() {
+        let config = "User root\n\
+                      Host web* !web2\n    Port 2200\n\
+                      Host web1\n    Port 2201\n    HostName=10.0.0.1\n\
+                      Match user alice\n    IdentityFile ~/.ssh/alice\n\
+                      # Comment\n\
+                      Host *\n    Port 22\n    IdentityFile ~/.ssh/id\n";
+        let lines = directive_lines(config.as_bytes(), "WEB1");
+        assert_eq!(lines["user"], (1, "*".to_string()));
+        assert_eq!(lines["port"], (3, "web* !web2".to_string()));
+        assert_eq!(lines["hostname"], (6, "web1".to_string()));
+        // The Match block is skipped
+        assert_eq!(lines["identityfile"], (12, "*".to_string()));
+
+        let lines = directive_lines(config.as_bytes(), "web2");
+        assert_eq!(lines["port"], (11, "*".to_string()));
+        assert!(!lines.contains_key("hostname"));
+    }
+}
@@ -0,0 +1,657 @@
+#[cfg(target_os = "linux")]
+use std::ffi::CString;
+use std::{
+    ffi::{c_char, c_int, c_void},
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6, TcpStream, ToSocketAddrs},
+    slice,
+    sync::Mutex,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+#[cfg(unix)]
+use std::os::fd::FromRawFd;
+
+use indicatif::ProgressBar;
+use log::{debug, info, warn};
+use ssh2::Session;
+
+use crate::{
+    auth::{audit_method, authenticate_all, AuditMethod},
+    cli::Options,
+    config::{AddressFamily, SessionPreferences},
+    dns::resolve_with,
+    proxy::open_tunnel,
+    summary::{ConnectTestSummary, Record},
+    timeline::Timeline,
+    util::{new_progress_bar, Formatter},
+};
+
+// Connect to the server and authenticate, returning the session along with
+// the time it took to authenticate and the method that succeeded
+pub fn connect_session(
+    opts: &Options,
+    preferences: &SessionPreferences,
+    timeline: &mut Timeline,
+) -> Result<(Session, Duration, &'static str), String> {
+    connect_session_with_spinner(opts, preferences, timeline, &ProgressBar::hidden())
+}
+
+// Same as connect_session, telling on the spinner which phase is running
+pub fn connect_session_with_spinner(
+    opts: &Options,
+    preferences: &SessionPreferences,
+    timeline: &mut Timeline,
+    spinner: &ProgressBar,
+) -> Result<(Session, Duration, &'static str), String> {
+    spinner.set_message(format!("Connecting to {}...", opts.target.host));
+    let session = handshake_session(opts, preferences, timeline)?;
+    spinner.set_message("Authenticating...");
+
+    // Try to authenticate with the server using the preferred methods
+    let phase_start = Instant::now();
+    let (ssh_connect_time, auth_method) = authenticate_all(&session, opts)
+        .map_err(|e| format!("Exiting due to authenticate: {e}"))?;
+    timeline.record("Authentication", phase_start, phase_start.elapsed());
+    // Make sure we succeeded
+    assert!(session.authenticated());
+    info!("Authenticated with {auth_method}");
+    Ok((session, ssh_connect_time, auth_method))
+}
+
+// Callback type of libssh2 for the disconnect message from the server
+const LIBSSH2_CALLBACK_DISCONNECT: c_int = 2;
+
+// Reason code and message of the last disconnect message from the server
+static DISCONNECT_REASON: Mutex<Option<(c_int, String)>> = Mutex::new(None);
+
+extern "C" {
+    // Exported by libssh2 but not bound by libssh2-sys
+    fn libssh2_session_callback_set(
+        session: *mut c_void,
+        cbtype: c_int,
+        callback: *mut c_void,
+    ) -> *mut c_void;
+}
+
+extern "C" fn on_disconnect(
+    _session: *mut c_void,
+    reason: c_int,
+    message: *const c_char,
+    message_len: c_int,
+    _language: *const c_char,
+    _language_len: c_int,
+    _abstract: *mut *mut c_void,
+) {
+    let message = if message.is_null() || message_len <= 0 {
+        String::new()
+    } else {
+        // SAFETY: libssh2 passes the message along with its length
+        let bytes = unsafe { slice::from_raw_parts(message as *const u8, message_len as usize) };
+        String::from_utf8_lossy(bytes).into_owned()
+    };
+    debug!("Server sent disconnect with reason {reason}: {message:?}");
+    *DISCONNECT_REASON.lock().unwrap() = Some((reason, message));
+}
+
+// Remember why the server disconnects, which libssh2 only reports as a
+// closed socket otherwise
+fn watch_disconnect(session: &Session) {
+    let mut raw = session.raw();
+    // SAFETY: the session pointer is valid while the guard is held, and the
+    // callback matches LIBSSH2_DISCONNECT_FUNC
+    unsafe {
+        libssh2_session_callback_set(
+            &mut *raw as *mut _ as *mut c_void,
+            LIBSSH2_CALLBACK_DISCONNECT,
+            on_disconnect as *const () as *mut c_void,
+        );
+    }
+}
+
+// Describe an error with the reason of the server if it disconnected us
+pub fn explain_disconnect(error: String) -> String {
+    let Some((reason, message)) = DISCONNECT_REASON.lock().unwrap().take() else {
+        return error;
+    };
+    let reason = match reason {
+        1 => "host not allowed to connect",
+        2 => "protocol error",
+        3 => "key exchange failed",
+        5 => "MAC error",
+        6 => "compression error",
+        7 => "service not available",
+        8 => "protocol version not supported",
+        9 => "host key not verifiable",
+        10 => "connection lost",
+        11 => "by application",
+        12 => "too many connections",
+        13 => "auth cancelled by user",
+        14 => "no more auth methods available",
+        15 => "illegal user name",
+        _ => "unknown reason",
+    };
+    match message.trim() {
+        "" => format!("Server disconnected: {reason} ({error})"),
+        message => format!("Server disconnected: {message}, {reason} ({error})"),
+    }
+}
+
+// Connect to the server and complete the handshake, without authenticating
+fn handshake_session(
+    opts: &Options,
+    preferences: &SessionPreferences,
+    timeline: &mut Timeline,
+) -> Result<Session, String> {
+    let phase_start = Instant::now();
+    let tcp = open_stream(opts, preferences)?;
+    timeline.record("Connect", phase_start, phase_start.elapsed());
+    if let Some(banner_timeout) = opts.banner_timeout {
+        let phase_start = Instant::now();
+        wait_for_banner(&tcp, Duration::from_secs_f64(banner_timeout))?;
+        timeline.record("Banner", phase_start, phase_start.elapsed());
+    }
+    let mut session = Session::new().map_err(|e| format!("Failed to create session: {e}"))?;
+    // The handshake is bound by --max-connect-time, everything after it by --ssh-timeout
+    let handshake_timeout = opts.max_connect_time.unwrap_or(opts.ssh_timeout);
+    // Rounded up, as a timeout of 0 means none at all to libssh2
+    session.set_timeout((handshake_timeout * 1000.0).ceil() as u32);
+    watch_disconnect(&session);
+    preferences.apply(&session);
+    // Keep a handle on the socket to ask the kernel about it afterwards
+    #[cfg(target_os = "linux")]
+    let socket = tcp.try_clone().ok();
+    session.set_tcp_stream(tcp);
+    let phase_start = Instant::now();
+    session
+        .handshake()
+        .map_err(|e| format!("Failed to handshake: {e}"))?;
+    let handshake_time = phase_start.elapsed();
+    #[cfg(target_os = "linux")]
+    if let Some(socket) = socket {
+        log_tcp_info(&socket);
+    }
+    session.set_timeout((opts.ssh_timeout * 1000.0).ceil() as u32);
+    timeline.record("Handshake", phase_start, handshake_time);
+    if let Some(threshold) = opts.warn_handshake_ms {
+        if handshake_time > Duration::from_millis(threshold) {
+            warn!(
+                "Handshake took {}ms, longer than {threshold}ms, the server or a middlebox may be slow",
+                handshake_time.as_millis()
+            );
+        }
+    }
+    Ok(session)
+}
+
+// Make sure FD is an open stream socket before taking ownership of it, so
+// that a wrong number fails cleanly instead of handing a file to libssh2
+#[cfg(unix)]
+fn check_inherited_socket(fd: c_int) -> Result<(), String> {
+    use std::mem::{size_of, MaybeUninit};
+
+    let mut stat = MaybeUninit::<libc::stat>::uninit();
+    // SAFETY: fstat fills the struct when it succeeds
+    if unsafe { libc::fstat(fd, stat.as_mut_ptr()) } < 0 {
+        return Err(format!(
+            "Unable to use socket {fd}: {}",
+            io::Error::last_os_error()
+        ));
+    }
+    // SAFETY: checked above that fstat succeeded
+    let stat = unsafe { stat.assume_init() };
+    if stat.st_mode & libc::S_IFMT != libc::S_IFSOCK {
+        return Err(format!("File descriptor {fd} is not a socket"));
+    }
+    let mut kind: c_int = 0;
+    let mut len = size_of::<c_int>() as libc::socklen_t;
+    // SAFETY: the kernel writes at most len bytes into kind
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            (&mut kind as *mut c_int).cast(),
+            &mut len,
+        )
+    };
+    if result < 0 {
+        return Err(format!(
+            "Unable to get type of socket {fd}: {}",
+            io::Error::last_os_error()
+        ));
+    }
+    if kind != libc::SOCK_STREAM {
+        return Err(format!("Socket {fd} is not a stream socket"));
+    }
+    Ok(())
+}
+
+fn open_stream(opts: &Options, preferences: &SessionPreferences) -> Result<TcpStream, String> {
+    #[cfg(unix)]
+    if let Some(fd) = opts.fd {
+        debug!("Using inherited socket {fd}");
+        check_inherited_socket(fd)?;
+        // SAFETY: the caller hands the socket over to us, and --fd conflicts
+        // with every mode that connects more than once
+        return Ok(unsafe { TcpStream::from_raw_fd(fd) });
+    }
+    // Retry like ssh does with ConnectionAttempts, one second apart
+    let attempts = preferences.connection_attempts.unwrap_or(1).max(1);
+    let mut attempt = 1;
+    loop {
+        match connect_stream(opts, preferences.address_family) {
+            Ok(tcp) => return Ok(tcp),
+            Err(e) if attempt < attempts => {
+                warn!("Connection attempt {attempt}/{attempts} failed: {e}");
+                attempt += 1;
+                sleep(Duration::from_secs(1));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn connect_stream(
+    opts: &Options,
+    address_family: Option<AddressFamily>,
+) -> Result<TcpStream, String> {
+    let host = &opts.target.host;
+    let timeout = opts.max_connect_time.map(Duration::from_secs_f64);
+    if let Some(proxy) = &opts.https_proxy {
+        debug!("Connecting through HTTP proxy {}", proxy.address());
+        let addrs = (proxy.host.as_str(), proxy.port)
+            .to_socket_addrs()
+            .map_err(|e| format!("Failed to resolve proxy {}: {e}", proxy.host))?
+            .collect::<Vec<_>>();
+        if addrs.is_empty() {
+            return Err(format!("No address found for proxy {}", proxy.host));
+        }
+        let tcp = match timeout {
+            Some(timeout) => connect_with_timeout(&addrs, timeout),
+            None => TcpStream::connect(&addrs[..]),
+        }
+        .map_err(|e| format!("Failed to connect to proxy: {e}"))?;
+        let proxy_timeout =
+            Duration::from_secs_f64(opts.max_connect_time.unwrap_or(opts.ssh_timeout));
+        return open_tunnel(tcp, proxy, host, opts.target.port, proxy_timeout);
+    }
+    let addrs = resolve(opts)?
+        .into_iter()
+        .filter(|addr| address_family.is_none_or(|family| family.matches(addr)))
+        .collect::<Vec<_>>();
+    if addrs.is_empty() {
+        return Err(format!("No matching address found for {host}"));
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(interface) = &opts.interface {
+        debug!("Binding to interface {interface}");
+        // Connect with SO_BINDTODEVICE set, which std offers no way to do
+        let mut last_error = None;
+        for &addr in &addrs {
+            match connect_addr_via_interface(addr, interface, timeout) {
+                Ok(tcp) => return Ok(tcp),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        return Err(format!(
+            "Failed to connect to server via {interface}: {}",
+            last_error.expect("At least one address was tried")
+        ));
+    }
+    match timeout {
+        Some(timeout) => connect_with_timeout(&addrs, timeout),
+        None => TcpStream::connect(&addrs[..]),
+    }
+    .map_err(|e| format!("Failed to connect to server: {e}"))
+}
+
+// Try each address in turn, giving up on each after the timeout
+fn connect_with_timeout(addrs: &[SocketAddr], timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for addr in addrs {
+        match TcpStream::connect_timeout(addr, timeout) {
+            Ok(tcp) => return Ok(tcp),
+            Err(e) => {
+                debug!("Failed to connect to {addr}: {e}");
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.expect("At least one address was tried"))
+}
+
+// Resolve the host, keeping the zone of a scoped IPv6 address which the
+// standard resolver rejects
+fn resolve(opts: &Options) -> Result<Vec<SocketAddr>, String> {
+    let (host, port) = (opts.target.host.as_str(), opts.target.port);
+    if let Some((address, zone)) = host.split_once('%') {
+        let address = address
+            .parse::<Ipv6Addr>()
+            .map_err(|e| format!("Invalid scoped IPv6 address {host}: {e}"))?;
+        let scope_id = match zone.parse::<u32>() {
+            Ok(index) => index,
+            Err(_) => interface_index(zone)?,
+        };
+        debug!("Using scope ID {scope_id} for zone {zone}");
+        return Ok(vec![SocketAddr::V6(SocketAddrV6::new(
+            address, port, 0, scope_id,
+        ))]);
+    }
+    if let Some(server) = opts.resolver.filter(|_| host.parse::<IpAddr>().is_err()) {
+        let timeout = Duration::from_secs_f64(opts.ssh_timeout);
+        let addrs = resolve_with(server, host, timeout)?
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect::<Vec<_>>();
+        if addrs.is_empty() {
+            return Err(format!("{server} has no address for {host}"));
+        }
+        return Ok(addrs);
+    }
+    Ok((host, port)
+        .to_socket_addrs()
+        .map_err(|e| format!("Failed to resolve {host}: {e}"))?
+        .collect())
+}
+
+#[cfg(target_os = "linux")]
+fn interface_index(interface: &str) -> Result<u32, String> {
+    let name = CString::new(interface).map_err(|e| format!("Invalid interface name: {e}"))?;
+    // SAFETY: the name is a valid NUL-terminated string
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(format!("Unknown interface {interface}")),
+        index => Ok(index),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn interface_index(interface: &str) -> Result<u32, String> {
+    Err(format!(
+        "Zone {interface} must be a numeric interface index on this platform"
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn connect_addr_via_interface(
+    addr: SocketAddr,
+    interface: &str,
+    timeout: Option<Duration>,
+) -> io::Result<TcpStream> {
+    use std::mem::{size_of, zeroed};
+
+    let domain = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    // SAFETY: plain socket call, the descriptor is owned by the stream right away
+    let fd = unsafe { libc::socket(domain, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+    // SAFETY: the option value is the interface name and its length
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            interface.as_ptr().cast(),
+            interface.len() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Linux bounds a blocking connect by the send timeout
+    stream.set_write_timeout(timeout)?;
+    // SAFETY: the socket addresses are zero initialized C structs
+    // filled in field by field, and passed along with their size
+    let result = unsafe {
+        match addr {
+            SocketAddr::V4(addr) => {
+                let mut sin: libc::sockaddr_in = zeroed();
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = addr.port().to_be();
+                sin.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+                libc::connect(
+                    fd,
+                    (&sin as *const libc::sockaddr_in).cast(),
+                    size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                )
+            }
+            SocketAddr::V6(addr) => {
+                let mut sin6: libc::sockaddr_in6 = zeroed();
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = addr.port().to_be();
+                sin6.sin6_flowinfo = addr.flowinfo();
+                sin6.sin6_addr.s6_addr = addr.ip().octets();
+                sin6.sin6_scope_id = addr.scope_id();
+                libc::connect(
+                    fd,
+                    (&sin6 as *const libc::sockaddr_in6).cast(),
+                    size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+                )
+            }
+        }
+    };
+    if result < 0 {
+        let error = io::Error::last_os_error();
+        // A connect cut short by the timeout reports it is still in progress
+        if error.raw_os_error() == Some(libc::EINPROGRESS) {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "connection timed out",
+            ));
+        }
+        return Err(error);
+    }
+    stream.set_write_timeout(None)?;
+    Ok(stream)
+}
+
+// Leading fields of struct tcp_info from linux/tcp.h, which the kernel
+// fills up to the length it is given
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Default)]
+struct TcpInfo {
+    _state: [u8; 2],
+    retransmits: u8,
+    _probes: [u8; 5],
+    _rto_to_sacked: [u32; 6],
+    lost: u32,
+    retrans: u32,
+    _fackets_to_rcv_ssthresh: [u32; 7],
+    rtt: u32,
+    rttvar: u32,
+    _snd_ssthresh_to_rcv_space: [u32; 6],
+    total_retrans: u32,
+}
+
+// Log the retransmissions and round trip time the kernel has seen on the
+// connection, which application level timings cannot tell
+#[cfg(target_os = "linux")]
+fn log_tcp_info(tcp: &TcpStream) {
+    use std::{mem::size_of, os::fd::AsRawFd};
+
+    let mut info = TcpInfo::default();
+    let mut len = size_of::<TcpInfo>() as libc::socklen_t;
+    // SAFETY: the kernel writes at most len bytes into the struct
+    let result = unsafe {
+        libc::getsockopt(
+            tcp.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            (&mut info as *mut TcpInfo).cast(),
+            &mut len,
+        )
+    };
+    if result < 0 {
+        debug!("Failed to get TCP info: {}", io::Error::last_os_error());
+        return;
+    }
+    info!(
+        "TCP after handshake: RTT {}us (variance {}us), {} retransmitted segments, {} lost, {} total retransmits, {} timeouts in a row",
+        info.rtt, info.rttvar, info.retrans, info.lost, info.total_retrans, info.retransmits
+    );
+}
+
+// Wait until the server starts sending its identification string
+fn wait_for_banner(tcp: &TcpStream, timeout: Duration) -> Result<(), String> {
+    tcp.set_read_timeout(Some(timeout))
+        .map_err(|e| format!("Failed to set banner timeout: {e}"))?;
+    let mut buffer = [0; 1];
+    match tcp.peek(&mut buffer) {
+        Ok(0) => return Err("Server closed the connection before sending its banner".into()),
+        Ok(_) => {}
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+            return Err(format!(
+                "Server did not send its banner within {}",
+                humantime::format_duration(timeout)
+            ))
+        }
+        Err(e) => return Err(format!("Failed to wait for server banner: {e}")),
+    }
+    debug!("Received server banner");
+    tcp.set_read_timeout(None)
+        .map_err(|e| format!("Failed to reset banner timeout: {e}"))
+}
+
+// Throwaway connections so that measurements do not pay for a cold start
+pub fn warm_up(
+    opts: &Options,
+    preferences: &SessionPreferences,
+    count: usize,
+) -> Result<(), String> {
+    info!("Warming up with {count} connections");
+    let progress_bar = new_progress_bar(count as u64, "Warmup", false);
+    for n in 0..count {
+        let (session, _, _) = connect_session(opts, preferences, &mut Timeline::start())?;
+        let _ = session.disconnect(None, "sshping warmup", None);
+        progress_bar.set_position((n as u64) + 1);
+    }
+    progress_bar.finish_and_clear();
+    Ok(())
+}
+
+// Repeatedly connect, authenticate and disconnect to benchmark connection setup
+pub fn run_connect_test(
+    opts: &Options,
+    preferences: &SessionPreferences,
+    count: usize,
+    formatter: &Formatter,
+) -> Result<ConnectTestSummary, String> {
+    info!("Running connect test");
+    debug!("Number of connections: {count}");
+    let mut setup_times = Vec::with_capacity(count);
+    let progress_bar = new_progress_bar(count as u64, "Connect test", false);
+    let start_time = Instant::now();
+    for n in 0..count {
+        let start = Instant::now();
+        let (session, _, _) = connect_session(opts, preferences, &mut Timeline::start())?;
+        setup_times.push(start.elapsed().as_nanos());
+        let _ = session.disconnect(None, "sshping connect test", None);
+        progress_bar.set_position((n as u64) + 1);
+    }
+    progress_bar.finish_and_clear();
+    let elapsed = start_time.elapsed();
+
+    setup_times.sort();
+    let result = ConnectTestSummary::new(&setup_times, elapsed, &opts.percentiles, formatter);
+    info!(
+        "Connected {} times, Rate: {}, Average: {}",
+        result.count, result.rate, result.setup.avg_latency
+    );
+    Ok(result)
+}
+
+// Try every authentication method on a fresh connection each, since a
+// session cannot authenticate again once one method succeeds
+pub fn run_auth_audit(
+    opts: &Options,
+    preferences: &SessionPreferences,
+) -> Result<Vec<Record>, String> {
+    info!("Running authentication audit");
+    let user = &opts.target.user;
+    let session = handshake_session(opts, preferences, &mut Timeline::start())?;
+    let offered = session
+        .auth_methods(user)
+        .map_err(|e| format!("Failed to query authentication methods: {e}"))?
+        .split(',')
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    let _ = session.disconnect(None, "sshping auth audit", None);
+    debug!("Offered authentication methods: {offered:?}");
+    let mut records = vec![];
+    for method in AuditMethod::ALL {
+        let result = if !offered.iter().any(|name| name == method.server_name()) {
+            "Not offered".to_string()
+        } else if !method.has_credential(opts) {
+            "Offered, no credential to try".to_string()
+        } else {
+            let session = handshake_session(opts, preferences, &mut Timeline::start())?;
+            let result = match audit_method(&session, user, method, opts) {
+                Ok(()) => "Accepted".to_string(),
+                Err(e) => format!("Rejected ({e})"),
+            };
+            let _ = session.disconnect(None, "sshping auth audit", None);
+            result
+        };
+        info!("{}: {result}", method.name());
+        records.push(Record::new("Auth audit", method.name(), result));
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    // Options for TARGET, with a config path that is sure to exist
+    fn options(target: &str) -> Options {
+        Options::parse_from(["sshping", "--config", env!("CARGO_MANIFEST_DIR"), target])
+    }
+
+    #[test]
+    fn resolve_keeps_zone() {
+        let opts = options("[fe80::1%5]:2222");
+        let addrs = resolve(&opts).unwrap();
+        assert_eq!(
+            addrs,
+            [SocketAddr::V6(SocketAddrV6::new(
+                "fe80::1".parse().unwrap(),
+                2222,
+                0,
+                5
+            ))]
+        );
+    }
+
+    #[test]
+    fn resolve_rejects_bad_zone_address() {
+        let opts = options("[10.0.0.1%5]:22");
+        assert!(resolve(&opts).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn inherited_socket_must_be_stream_socket() {
+        use std::{
+            fs::File,
+            net::{TcpListener, UdpSocket},
+            os::fd::AsRawFd,
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        assert!(check_inherited_socket(listener.as_raw_fd()).is_ok());
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(check_inherited_socket(udp.as_raw_fd()).is_err());
+        let file = File::open(env!("CARGO_MANIFEST_DIR")).unwrap();
+        assert!(check_inherited_socket(file.as_raw_fd()).is_err());
+        assert!(check_inherited_socket(c_int::MAX).is_err());
+    }
+}
@@ -0,0 +1,274 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+use log::debug;
+use rand::random;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+// Resolve a host name through the given DNS server instead of the system
+// resolver, returning both IPv4 and IPv6 addresses. Many servers drop AAAA
+// queries, so this only fails if neither query is answered
+pub fn resolve_with(
+    server: SocketAddr,
+    host: &str,
+    timeout: Duration,
+) -> Result<Vec<IpAddr>, String> {
+    let mut addrs = vec![];
+    let mut error = None;
+    let mut answered = false;
+    for record_type in [TYPE_A, TYPE_AAAA] {
+        match query(server, host, record_type, timeout) {
+            Ok(found) => {
+                answered = true;
+                addrs.extend(found);
+            }
+            Err(e) => {
+                debug!("Query of type {record_type} for {host} via {server} failed: {e}");
+                error.get_or_insert(e);
+            }
+        }
+    }
+    match error {
+        Some(e) if !answered => Err(e),
+        _ => {
+            debug!("Resolved {host} via {server}: {addrs:?}");
+            Ok(addrs)
+        }
+    }
+}
+
+fn query(
+    server: SocketAddr,
+    host: &str,
+    record_type: u16,
+    timeout: Duration,
+) -> Result<Vec<IpAddr>, String> {
+    let local: SocketAddr = match server {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    let socket = UdpSocket::bind(local).map_err(|e| e.to_string())?;
+    socket
+        .set_read_timeout(Some(timeout))
+        .map_err(|e| e.to_string())?;
+    let id = random::<u16>();
+    let request = build_query(id, host, record_type)?;
+    socket
+        .send_to(&request, server)
+        .map_err(|e| format!("Failed to query {server}: {e}"))?;
+    let mut buf = [0u8; 1500];
+    loop {
+        let (len, from) = socket
+            .recv_from(&mut buf)
+            .map_err(|e| format!("No answer from {server}: {e}"))?;
+        // Ignore stray packets that are not the answer to our query
+        if from == server && len >= 12 && u16::from_be_bytes([buf[0], buf[1]]) == id {
+            return parse_answer(&buf[..len], &request, record_type);
+        }
+    }
+}
+
+fn build_query(id: u16, host: &str, record_type: u16) -> Result<Vec<u8>, String> {
+    let mut packet = vec![];
+    packet.extend(id.to_be_bytes());
+    // Recursion desired, one question
+    packet.extend([0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("Invalid host name {host}"));
+        }
+        packet.push(label.len() as u8);
+        packet.extend(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend(record_type.to_be_bytes());
+    packet.extend(CLASS_IN.to_be_bytes());
+    Ok(packet)
+}
+
+// Skip over a possibly compressed name, returning the offset after it
+fn skip_name(packet: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *packet.get(offset)? as usize;
+        match len {
+            0 => return Some(offset + 1),
+            // A pointer ends the name
+            l if l & 0xC0 == 0xC0 => return Some(offset + 2),
+            l => offset += l + 1,
+        }
+    }
+}
+
+// Addresses of RECORD_TYPE in the answer to the query REQUEST
+fn parse_answer(packet: &[u8], request: &[u8], record_type: u16) -> Result<Vec<IpAddr>, String> {
+    let malformed = || "Malformed DNS answer".to_string();
+    let read_u16 = |offset: usize| -> Result<u16, String> {
+        packet
+            .get(offset..offset + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .ok_or_else(malformed)
+    };
+    let flags = read_u16(2)?;
+    if flags & 0x8000 == 0 {
+        return Err("DNS server sent a query instead of an answer".to_string());
+    }
+    if flags & 0x0200 != 0 {
+        return Err("DNS answer was truncated".to_string());
+    }
+    match flags & 0x000F {
+        0 => {}
+        // Name does not exist
+        3 => return Ok(vec![]),
+        code => return Err(format!("DNS server returned error code {code}")),
+    }
+    // The answer must repeat the question that was asked
+    let question = &request[12..];
+    let echoed = packet.get(12..12 + question.len()).ok_or_else(malformed)?;
+    if read_u16(4)? != 1 || !echoed.eq_ignore_ascii_case(question) {
+        return Err("DNS answer is for a different question".to_string());
+    }
+    let answers = read_u16(6)?;
+    let mut offset = 12 + question.len();
+    let mut addrs = vec![];
+    for _ in 0..answers {
+        offset = skip_name(packet, offset).ok_or_else(malformed)?;
+        let answer_type = read_u16(offset)?;
+        let length = read_u16(offset + 8)? as usize;
+        offset += 10;
+        let data = packet.get(offset..offset + length).ok_or_else(malformed)?;
+        offset += length;
+        // Answers may also hold the CNAME chain leading to the address
+        if answer_type != record_type {
+            continue;
+        }
+        let addr = match record_type {
+            TYPE_A => <[u8; 4]>::try_from(data).map(|octets| IpAddr::V4(Ipv4Addr::from(octets))),
+            _ => <[u8; 16]>::try_from(data).map(|octets| IpAddr::V6(Ipv6Addr::from(octets))),
+        };
+        addrs.push(addr.map_err(|_| malformed())?);
+    }
+    Ok(addrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: u16 = 0x1234;
+
+    // Answer to REQUEST with FLAGS and records of the given type and data,
+    // each named by a pointer to the question
+    fn answer(request: &[u8], flags: u16, records: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut packet = request.to_vec();
+        packet[2..4].copy_from_slice(&flags.to_be_bytes());
+        packet[6..8].copy_from_slice(&(records.len() as u16).to_be_bytes());
+        for (record_type, data) in records {
+            packet.extend([0xC0, 12]);
+            packet.extend(record_type.to_be_bytes());
+            packet.extend(CLASS_IN.to_be_bytes());
+            // TTL
+            packet.extend([0, 0, 0, 60]);
+            packet.extend((data.len() as u16).to_be_bytes());
+            packet.extend(*data);
+        }
+        packet
+    }
+
+    #[test]
+    fn query_packet() {
+        let request = build_query(ID, "example.com.", TYPE_AAAA).unwrap();
+        assert_eq!(
+            request,
+            b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\
+              \x07example\x03com\x00\x00\x1c\x00\x01"
+        );
+        assert!(build_query(ID, "example..com", TYPE_A).is_err());
+        assert!(build_query(ID, &"a".repeat(64), TYPE_A).is_err());
+    }
+
+    #[test]
+    fn a_answer() {
+        let request = build_query(ID, "example.com", TYPE_A).unwrap();
+        let packet = answer(&request, 0x8180, &[(TYPE_A, &[192, 0, 2, 1])]);
+        assert_eq!(
+            parse_answer(&packet, &request, TYPE_A),
+            Ok(vec![IpAddr::from([192, 0, 2, 1])])
+        );
+    }
+
+    #[test]
+    fn aaaa_answer() {
+        let request = build_query(ID, "example.com", TYPE_AAAA).unwrap();
+        let addr: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let packet = answer(&request, 0x8180, &[(TYPE_AAAA, &addr.octets())]);
+        assert_eq!(
+            parse_answer(&packet, &request, TYPE_AAAA),
+            Ok(vec![IpAddr::V6(addr)])
+        );
+    }
+
+    #[test]
+    fn cname_chain() {
+        let request = build_query(ID, "www.example.com", TYPE_A).unwrap();
+        // The canonical name reuses the end of the question, at offset 16
+        let packet = answer(
+            &request,
+            0x8180,
+            &[
+                (5, b"\x03web\xC0\x10"),
+                (TYPE_A, &[192, 0, 2, 1]),
+                (TYPE_A, &[192, 0, 2, 2]),
+            ],
+        );
+        assert_eq!(
+            parse_answer(&packet, &request, TYPE_A),
+            Ok(vec![
+                IpAddr::from([192, 0, 2, 1]),
+                IpAddr::from([192, 0, 2, 2])
+            ])
+        );
+    }
+
+    #[test]
+    fn nxdomain_and_errors() {
+        let request = build_query(ID, "missing.example", TYPE_A).unwrap();
+        assert_eq!(
+            parse_answer(&answer(&request, 0x8183, &[]), &request, TYPE_A),
+            Ok(vec![])
+        );
+        // Server failure
+        assert!(parse_answer(&answer(&request, 0x8182, &[]), &request, TYPE_A).is_err());
+    }
+
+    #[test]
+    fn truncated_answer() {
+        let request = build_query(ID, "example.com", TYPE_A).unwrap();
+        let packet = answer(&request, 0x8380, &[(TYPE_A, &[192, 0, 2, 1])]);
+        assert!(parse_answer(&packet, &request, TYPE_A).is_err());
+    }
+
+    #[test]
+    fn malformed_answers() {
+        let request = build_query(ID, "example.com", TYPE_A).unwrap();
+        let packet = answer(&request, 0x8180, &[(TYPE_A, &[192, 0, 2, 1])]);
+        // Cut short anywhere
+        for len in 0..packet.len() {
+            assert!(parse_answer(&packet[..len], &request, TYPE_A).is_err());
+        }
+        // Not an answer
+        let query = answer(&request, 0x0100, &[(TYPE_A, &[192, 0, 2, 1])]);
+        assert!(parse_answer(&query, &request, TYPE_A).is_err());
+        // Answer to another question
+        let other = build_query(ID, "example.org", TYPE_A).unwrap();
+        let packet = answer(&other, 0x8180, &[(TYPE_A, &[192, 0, 2, 1])]);
+        assert!(parse_answer(&packet, &request, TYPE_A).is_err());
+        // Address of the wrong length
+        let packet = answer(&request, 0x8180, &[(TYPE_A, &[192, 0, 2])]);
+        assert!(parse_answer(&packet, &request, TYPE_A).is_err());
+    }
+}
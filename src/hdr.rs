@@ -0,0 +1,59 @@
+use std::{fmt::Write, fs::write, path::Path};
+
+use hdrhistogram::Histogram;
+
+// Three significant digits, the usual choice for latency histograms
+const SIGFIG: u8 = 3;
+// Latencies are recorded in nanoseconds but written in milliseconds
+const SCALE: f64 = 1e6;
+
+// Write latencies in the HdrHistogram percentile distribution text format,
+// which plotting tools for HdrHistogram logs read directly
+pub fn write_hdr_output(path: &Path, latencies: &[u128]) -> Result<(), String> {
+    let mut histogram = Histogram::<u64>::new(SIGFIG).map_err(|e| e.to_string())?;
+    for &latency in latencies {
+        histogram
+            .record(latency as u64)
+            .map_err(|e| e.to_string())?;
+    }
+    let mut output = format!(
+        "{:>12} {:>14} {:>10} {:>14}\n\n",
+        "Value", "Percentile", "TotalCount", "1/(1-Percentile)"
+    );
+    let mut total_count = 0;
+    for value in histogram.iter_quantiles(5) {
+        total_count += value.count_since_last_iteration();
+        let quantile = value.quantile_iterated_to();
+        let _ = write!(
+            output,
+            "{:12.3} {:2.12} {:10}",
+            value.value_iterated_to() as f64 / SCALE,
+            quantile,
+            total_count
+        );
+        if quantile < 1.0 {
+            let _ = write!(output, " {:14.2}", 1.0 / (1.0 - quantile));
+        }
+        output.push('\n');
+    }
+    let sub_buckets = (2 * 10u32.pow(SIGFIG.into())).next_power_of_two();
+    let _ = writeln!(
+        output,
+        "#[Mean    = {:12.3}, StdDeviation   = {:12.3}]",
+        histogram.mean() / SCALE,
+        histogram.stdev() / SCALE
+    );
+    let _ = writeln!(
+        output,
+        "#[Max     = {:12.3}, Total count    = {:12}]",
+        histogram.max() as f64 / SCALE,
+        histogram.len()
+    );
+    let _ = writeln!(
+        output,
+        "#[Buckets = {:12}, SubBuckets     = {:12}]",
+        histogram.buckets(),
+        sub_buckets
+    );
+    write(path, output).map_err(|e| format!("Failed to write {path:?}: {e}"))
+}
@@ -1,32 +1,61 @@
 mod auth;
+mod benchmark;
 mod cli;
+mod compare;
+mod config;
+mod connect;
+mod dns;
+mod hdr;
+mod proxy;
+mod report;
 mod style;
 mod summary;
 mod tests;
+mod timeline;
 mod util;
 
 use std::{
-    fs::File,
-    io::{stdout, BufReader, Read},
-    net::TcpStream,
+    fs::{write, File},
+    io::{stderr, stdin, stdout, BufReader, IsTerminal, Read},
+    path::PathBuf,
     process::{exit, ExitCode},
+    sync::mpsc::channel,
+    thread::spawn,
+    time::{Duration, Instant},
 };
 
-use auth::authenticate_all;
+use benchmark::{run_cipher_benchmark, supported_ciphers};
 use clap::{CommandFactory, Parser};
 use clap_complete::generate;
-use cli::{Options, Test};
-use log::{debug, error, trace, LevelFilter};
+use cli::{Options, ProgressDisplay, Protocol, Test};
+use compare::run_target;
+use config::{
+    apply_host_params, directive_lines, ignored_directives, ConfigOrigins, Origin,
+    SessionPreferences,
+};
+use connect::{
+    connect_session_with_spinner, explain_disconnect, run_auth_audit, run_connect_test, warm_up,
+};
+use humantime::format_rfc3339_millis;
+use log::{debug, error, info, trace, warn, LevelFilter};
+use report::{emit, pipe_output_to, send_to_syslog};
 use simple_logger::SimpleLogger;
-use ssh2::Session;
 use ssh2_config::{ParseRule, SshConfig};
-use summary::Record;
+use summary::{template_fields, Comparison, EchoTestSummary, Record, SpeedTestSummary};
 use tabled::{
+    builder::Builder,
     settings::{style::BorderSpanCorrection, Alignment, Span},
     Table,
 };
-use tests::{run_echo_test, run_speed_test};
-use util::Formatter;
+use tests::{
+    chunk_size_for_rtt, probe_rtt, remove_remote_file, run_echo_test, run_exec_probe,
+    run_payload_sweep, run_preflight, run_speed_test, unique_remote_file,
+};
+use timeline::Timeline;
+use util::{
+    acquire_lock, expand_tokens, new_spinner, push_progress_summary, render_template,
+    run_local_command, set_progress_display, Formatter,
+};
 
 fn main() -> ExitCode {
     let mut opts = Options::parse();
@@ -52,112 +81,334 @@ fn main() -> ExitCode {
         .init()
         .unwrap();
 
-    // Get the formatter for output
-    let formatter = Formatter::new(opts.human_readable, opts.delimiter);
+    // Only draw progress to an interactive terminal
+    set_progress_display(if opts.no_progress || !stderr().is_terminal() {
+        ProgressDisplay::None
+    } else {
+        opts.progress_style
+    });
 
-    // Respect the SSH configuration file if it exists
-    if opts.config.exists() {
-        debug!("SSH Config: {:?}", opts.config);
-        let mut reader =
-            BufReader::new(File::open(&opts.config).expect("Could not open configuration file"));
-        let config = SshConfig::default()
-            .parse(&mut reader, ParseRule::ALLOW_UNKNOWN_FIELDS)
-            .expect("Failed to parse configuration");
-        // Query attributes for host
-        let params = config.query(opts.target.host.as_str());
-        // Update options with configuration
-        if let Some(host) = params.host_name {
-            opts.target.host = host;
-        }
-        if let Some(user) = params.user {
-            opts.target.user = user;
-        }
-        if let Some(port) = params.port {
-            opts.target.port = port;
+    // Keep other runs out until this one exits
+    let _lock = match &opts.lock_file {
+        Some(path) => match acquire_lock(path, opts.lock_timeout.map(Duration::from_secs_f64)) {
+            Ok(lock) => Some(lock),
+            Err(e) => {
+                error!("Failed to lock {path:?}: {e}");
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
+
+    // Send results to another program if requested, waiting for it on exit
+    let _pipe = match opts.pipe_to.as_deref().map(pipe_output_to).transpose() {
+        Ok(pipe) => pipe,
+        Err(e) => {
+            error!("Failed to start output command: {e}");
+            return ExitCode::FAILURE;
         }
-        if let Some(identity) = params.identity_file {
-            opts.identity = Some(identity[0].to_owned());
+    };
+
+    // Get the formatter for output
+    let formatter = Formatter::new(
+        opts.human_readable,
+        opts.delimiter,
+        opts.duration_style,
+        opts.output_precision,
+        opts.show_exact_bytes,
+    );
+
+    // Catch mistakes in the template before running any test
+    if let Some(template) = &opts.template {
+        if let Err(e) = render_template(
+            template,
+            &output_fields(&opts, &formatter, None, None, None, None),
+        ) {
+            error!("Invalid template: {e}");
+            return ExitCode::FAILURE;
         }
     }
 
-    trace!("Options: {:?}", opts);
-    debug!("User: {}", opts.target.user);
-    debug!("Host: {}", opts.target.host);
-    debug!("Port: {}", opts.target.port);
+    // Refuse to generate speed test data that does not fit in memory
+    if opts.run_tests != Test::Echo && opts.size > opts.max_memory {
+        error!(
+            "File size {} exceeds memory limit {}, use a smaller --size or raise --max-memory",
+            formatter.format_size(opts.size),
+            formatter.format_size(opts.max_memory)
+        );
+        return ExitCode::FAILURE;
+    }
 
-    // Connect to the local SSH server
-    let tcp = match TcpStream::connect(format!("{}:{}", opts.target.host, opts.target.port)) {
-        Ok(tcp) => tcp,
-        Err(e) => {
-            error!("Failed to connect to server: {e}");
-            return ExitCode::FAILURE;
+    // Leave something of the file to measure after the warmup, unless the
+    // transfer is bound by time rather than by --size
+    if opts.run_tests != Test::Echo
+        && opts.speed_duration.is_none()
+        && opts.speed_warmup_bytes > 0
+        && opts.speed_warmup_bytes >= opts.size
+    {
+        error!(
+            "Speed warmup of {} leaves nothing of the {} file to measure",
+            formatter.format_size(opts.speed_warmup_bytes),
+            formatter.format_size(opts.size)
+        );
+        return ExitCode::FAILURE;
+    }
+
+    // SCP has to announce the upload size before sending anything
+    if opts.speed_duration.is_some() && opts.protocol != Protocol::Sftp {
+        error!("--speed-duration requires --protocol sftp");
+        return ExitCode::FAILURE;
+    }
+
+    // Only SFTP can set the size of the remote file
+    if opts.preallocate && opts.protocol != Protocol::Sftp {
+        error!("--preallocate requires --protocol sftp");
+        return ExitCode::FAILURE;
+    }
+
+    // Only SFTP can ask the server to sync the file
+    if opts.fsync && opts.protocol != Protocol::Sftp {
+        error!("--fsync requires --protocol sftp");
+        return ExitCode::FAILURE;
+    }
+
+    // Only compare two targets if requested
+    if let Some(targets) = opts.compare.clone() {
+        let mut results = vec![];
+        for target in targets {
+            let name = target.host.clone();
+            let mut target_opts = opts.clone();
+            target_opts.target = target;
+            let result = resolve_target(&mut target_opts).and_then(|(preferences, cleanup)| {
+                run_target(&target_opts, &preferences, cleanup, &formatter)
+            });
+            match result {
+                Ok(result) => results.push((name, result)),
+                Err(e) => {
+                    error!("Failed to test {name}: {}", explain_disconnect(e));
+                    return ExitCode::FAILURE;
+                }
+            }
         }
-    };
-    let mut session = match Session::new() {
-        Ok(session) => session,
+        let comparisons = Comparison::between(&results[0].1, &results[1].1, &formatter);
+        print_comparison(&results[0].0, &results[1].0, comparisons, &opts);
+        return ExitCode::SUCCESS;
+    }
+
+    let (preferences, generated_remote_file) = match resolve_target(&mut opts) {
+        Ok(resolved) => resolved,
         Err(e) => {
-            error!("Failed to create session: {e}");
+            error!("{e}");
             return ExitCode::FAILURE;
         }
     };
-    session.set_timeout((opts.ssh_timeout * 1000.0) as u32);
-    session.set_tcp_stream(tcp);
-    match session.handshake() {
-        Ok(_) => {}
-        Err(e) => {
-            error!("Failed to handshake: {e}");
+
+    if opts.warmup_connections > 0 {
+        if let Err(e) = warm_up(&opts, &preferences, opts.warmup_connections) {
+            error!("Failed to warm up: {}", explain_disconnect(e));
             return ExitCode::FAILURE;
         }
     }
 
-    // Try to authenticate with the server using:
-    // 1) identity in the agent;
-    // 2) specified identity;
-    // 3) password
-    let ssh_connect_time = match authenticate_all(
-        &session,
-        &opts.target.user,
-        opts.password.as_deref(),
-        opts.identity.as_ref(),
-    ) {
-        Ok(time) => time,
+    // Connect to the SSH server
+    let mut timeline = Timeline::start();
+    // Only benchmark connection setup if requested
+    if let Some(count) = opts.connect_repeat {
+        let result = match run_connect_test(&opts, &preferences, count, &formatter) {
+            Ok(result) => result,
+            Err(e) => {
+                error!("Failed to finish connect test: {}", explain_disconnect(e));
+                return ExitCode::FAILURE;
+            }
+        };
+        print_table(vec![result.to_formatted_frame()], &opts);
+        return ExitCode::SUCCESS;
+    }
+    // Only audit authentication methods if requested
+    if opts.audit_auth {
+        let records = match run_auth_audit(&opts, &preferences) {
+            Ok(records) => records,
+            Err(e) => {
+                error!(
+                    "Failed to finish authentication audit: {}",
+                    explain_disconnect(e)
+                );
+                return ExitCode::FAILURE;
+            }
+        };
+        print_table(vec![records], &opts);
+        return ExitCode::SUCCESS;
+    }
+    // Only benchmark ciphers if requested
+    if let Some(ciphers) = &opts.benchmark_ciphers {
+        let ciphers = if ciphers.is_empty() {
+            match supported_ciphers() {
+                Ok(ciphers) => ciphers,
+                Err(e) => {
+                    error!("Failed to list supported ciphers: {e}");
+                    return ExitCode::FAILURE;
+                }
+            }
+        } else {
+            ciphers.clone()
+        };
+        debug!("Ciphers: {}", ciphers.join(","));
+        let groups = run_cipher_benchmark(
+            &opts,
+            &preferences,
+            &ciphers,
+            generated_remote_file,
+            &formatter,
+        );
+        print_table(groups, &opts);
+        return ExitCode::SUCCESS;
+    }
+    let spinner = new_spinner();
+    let connection = connect_session_with_spinner(&opts, &preferences, &mut timeline, &spinner);
+    spinner.finish_and_clear();
+    let (mut session, ssh_connect_time, auth_method) = match connection {
+        Ok(connection) => connection,
         Err(e) => {
-            error!("Exiting due to authenticate: {e}");
+            error!("{}", explain_disconnect(e));
             return ExitCode::FAILURE;
         }
     };
-    // Make sure we succeeded
-    assert!(session.authenticated());
+    push_progress_summary(format!(
+        "connect: {}",
+        formatter.format_duration(ssh_connect_time)
+    ));
+
+    // Run the local command now that we are connected
+    if let Some(command) = &opts.local_command {
+        let port = opts.target.port.to_string();
+        let tokens = [
+            ('h', opts.target.host.as_str()),
+            ('p', port.as_str()),
+            ('r', opts.target.user.as_str()),
+        ];
+        let command = match expand_tokens(command, &tokens) {
+            Ok(command) => command,
+            Err(e) => {
+                error!("Invalid local command: {e}");
+                return ExitCode::FAILURE;
+            }
+        };
+        debug!("Running local command: {command:?}");
+        match run_local_command(&command) {
+            Ok(0) => {}
+            Ok(code) => warn!("Local command exited with status {code}"),
+            Err(e) => warn!("Failed to run local command: {e}"),
+        }
+    }
+
+    // Only sweep echo payload sizes if requested
+    if let Some(sizes) = &opts.payload_sweep {
+        let records = match run_payload_sweep(&session, &opts, sizes, &formatter) {
+            Ok(records) => records,
+            Err(e) => {
+                error!("Failed to finish payload sweep: {}", explain_disconnect(e));
+                return ExitCode::FAILURE;
+            }
+        };
+        print_table(vec![records], &opts);
+        return ExitCode::SUCCESS;
+    }
+
+    // Only probe command execution latency if requested
+    if opts.probe_only {
+        let records = match run_exec_probe(&session, &opts, &formatter) {
+            Ok(records) => records,
+            Err(e) => {
+                error!("Failed to finish exec probe: {}", explain_disconnect(e));
+                return ExitCode::FAILURE;
+            }
+        };
+        print_table(vec![records], &opts);
+        return ExitCode::SUCCESS;
+    }
+
+    if opts.preflight {
+        if let Err(e) = run_preflight(&session, &opts, generated_remote_file) {
+            error!("Preflight check failed: {}", explain_disconnect(e));
+            return ExitCode::FAILURE;
+        }
+    }
 
     // Running tests
     let echo_test_result = if opts.run_tests == Test::Echo || opts.run_tests == Test::Both {
-        match run_echo_test(
-            &session,
-            &opts.echo_cmd,
-            opts.char_count,
-            opts.echo_timeout,
-            &formatter,
-        ) {
+        let phase_start = Instant::now();
+        let result = match run_echo_test(&session, &opts, &formatter) {
             Ok(result) => Some(result),
             Err(e) => {
-                error!("Failed to finish echo test: {e}");
+                error!("Failed to finish echo test: {}", explain_disconnect(e));
                 return ExitCode::FAILURE;
             }
+        };
+        timeline.record("Echo test", phase_start, phase_start.elapsed());
+        if let Some(result) = &result {
+            push_progress_summary(format!("echo: {} avg", result.avg_latency));
         }
+        result
     } else {
         None
     };
+    let mut speed_connect_time = None;
     let speed_test_result = if opts.run_tests == Test::Speed || opts.run_tests == Test::Both {
-        match run_speed_test(
-            &session,
-            opts.size,
-            opts.chunk_size,
-            &opts.remote_file,
-            &formatter,
-        ) {
+        // Start over so that the echo test does not warm up the connection
+        if opts.reconnect_per_test && echo_test_result.is_some() {
+            let _ = session.disconnect(None, "sshping reconnect", None);
+            let spinner = new_spinner();
+            let connection =
+                connect_session_with_spinner(&opts, &preferences, &mut timeline, &spinner);
+            spinner.finish_and_clear();
+            session = match connection {
+                Ok((session, connect_time, _)) => {
+                    speed_connect_time = Some(connect_time);
+                    session
+                }
+                Err(e) => {
+                    error!("{}", explain_disconnect(e));
+                    return ExitCode::FAILURE;
+                }
+            };
+        }
+        // Size chunks after the round trip time of this link
+        if opts.auto_chunk_size {
+            let rtt = match &echo_test_result {
+                Some(result) => Ok(Duration::from_nanos(result.avg_nanos as u64)),
+                None => probe_rtt(&session, &opts),
+            };
+            match rtt {
+                Ok(rtt) => {
+                    opts.chunk_size = chunk_size_for_rtt(rtt);
+                    info!(
+                        "Using chunk size {} for round trip time {}",
+                        formatter.format_size(opts.chunk_size),
+                        formatter.format_duration(rtt)
+                    );
+                }
+                Err(e) => {
+                    error!(
+                        "Failed to measure round trip time: {}",
+                        explain_disconnect(e)
+                    );
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        let result = run_speed_test(&session, &opts, &formatter);
+        // Clean up the generated remote file even if the test failed
+        if generated_remote_file {
+            if let Some(remote_file) = &opts.remote_file {
+                if let Err(e) = remove_remote_file(&session, remote_file) {
+                    warn!("Failed to remove remote file {remote_file:?}: {e}");
+                }
+            }
+        }
+        match result {
             Ok(result) => Some(result),
             Err(e) => {
-                error!("Failed to finish speed test: {e}");
+                error!("Failed to finish speed test: {}", explain_disconnect(e));
                 return ExitCode::FAILURE;
             }
         }
@@ -166,22 +417,334 @@ fn main() -> ExitCode {
     };
 
     // Output results
-    let mut data = vec![Record::new(
-        "SSH",
-        "Connect time",
-        formatter.format_duration(ssh_connect_time),
-    )];
-    let mut modifications = vec![];
-    let mut row_count = 1;
+    let line = opts.template.as_ref().map(|template| {
+        let fields = output_fields(
+            &opts,
+            &formatter,
+            Some(ssh_connect_time),
+            Some(auth_method),
+            echo_test_result.as_ref(),
+            speed_test_result.as_ref(),
+        );
+        render_template(template, &fields).expect("Template was checked before running")
+    });
+    let mut groups = vec![match speed_connect_time {
+        Some(speed_connect_time) => vec![
+            Record::new(
+                "SSH",
+                "Connect time (echo)",
+                formatter.format_duration(ssh_connect_time),
+            ),
+            Record::new(
+                "SSH",
+                "Connect time (speed)",
+                formatter.format_duration(speed_connect_time),
+            ),
+        ],
+        None => vec![Record::new(
+            "SSH",
+            "Connect time",
+            formatter.format_duration(ssh_connect_time),
+        )],
+    }];
+    groups[0].push(Record::new("SSH", "Auth method", auth_method.to_string()));
     if let Some(result) = echo_test_result {
-        let records = result.to_formatted_frame();
-        modifications.push(((row_count + 1, 0), Span::row(records.len())));
-        row_count += records.len();
-        data.extend(records);
+        let mut records = result.to_formatted_frame();
+        // Tell how many echoes it took to converge
+        if opts.adaptive_echo.is_some() {
+            records.insert(
+                0,
+                Record::new("Latency", "Echoes", result.char_sent.to_string()),
+            );
+        }
+        groups.push(records);
     }
     if let Some(result) = speed_test_result {
-        let records = result.to_formatted_frame();
-        modifications.push(((row_count + 1, 0), Span::row(records.len())));
+        if let Some(upload) = &result.upload {
+            timeline.record("Upload", upload.started_at, upload.elapsed);
+        }
+        timeline.record(
+            "Download",
+            result.download.started_at,
+            result.download.elapsed,
+        );
+        if let Some(baseline) = &result.baseline {
+            timeline.record(
+                "Upload (memory)",
+                baseline.upload.started_at,
+                baseline.upload.elapsed,
+            );
+            timeline.record(
+                "Download (memory)",
+                baseline.download.started_at,
+                baseline.download.elapsed,
+            );
+        }
+        groups.push(result.to_formatted_frame());
+    }
+    if opts.timestamps {
+        groups.push(
+            timeline
+                .timestamps()
+                .into_iter()
+                .flat_map(|(name, start, end)| {
+                    [
+                        Record::new(name, "Started at", format_rfc3339_millis(start).to_string()),
+                        Record::new(name, "Ended at", format_rfc3339_millis(end).to_string()),
+                    ]
+                })
+                .collect(),
+        );
+    }
+    if let Some(server) = &opts.syslog {
+        if let Err(e) = send_to_syslog(&groups, &opts.target.host, server.as_deref()) {
+            error!("Failed to send results to syslog: {e}");
+            return ExitCode::FAILURE;
+        }
+    }
+    if !opts.syslog_only {
+        match line {
+            Some(line) => emit(&line),
+            None => print_table(groups, &opts),
+        }
+    }
+
+    // Show the timeline of all phases
+    if let Some(style) = opts.timeline {
+        let rendered = timeline.render(style, &formatter);
+        match &opts.timeline_output {
+            Some(path) => {
+                if let Err(e) = write(path, rendered + "\n") {
+                    error!("Failed to write timeline to {path:?}: {e}");
+                    return ExitCode::FAILURE;
+                }
+            }
+            None => emit(&rendered),
+        }
+    }
+
+    // Waiting for key input before exiting, only when someone can press it
+    if opts.key_wait {
+        if stdin().is_terminal() {
+            println!("Press enter to exit...");
+            let (sender, receiver) = channel();
+            spawn(move || {
+                let mut buf = [0u8; 1];
+                let _ = stdin().read(&mut buf);
+                let _ = sender.send(());
+            });
+            let timeout = Duration::from_secs_f64(opts.key_wait_timeout);
+            if receiver.recv_timeout(timeout).is_err() {
+                debug!("Timed out waiting for key input");
+            }
+        } else {
+            debug!("Skipping key wait as stdin is not a terminal");
+        }
+    }
+
+    // Exit successfully
+    ExitCode::SUCCESS
+}
+
+// Apply the SSH configuration and ssh options for the target, then expand
+// tokens, returning the preferences and whether the remote file is generated
+fn resolve_target(opts: &mut Options) -> Result<(SessionPreferences, bool), String> {
+    // Host patterns are always matched against the alias given by the user
+    let alias = opts.target.host.clone();
+    let mut preferences = opts
+        .preset
+        .map(SessionPreferences::from_preset)
+        .unwrap_or_default();
+    // Track where each setting comes from if asked to show it
+    let mut origins = opts.debug_config.then(|| ConfigOrigins::new(opts));
+    // Respect the SSH configuration file if it exists
+    if opts.config.exists() {
+        debug!("SSH Config: {:?}", opts.config);
+        let mut reader =
+            BufReader::new(File::open(&opts.config).expect("Could not open configuration file"));
+        let config = SshConfig::default()
+            .parse(
+                &mut reader,
+                ParseRule::ALLOW_UNKNOWN_FIELDS | ParseRule::ALLOW_UNSUPPORTED_FIELDS,
+            )
+            .expect("Failed to parse configuration");
+        // Query attributes for host
+        let params = config.query(alias.as_str());
+        check_ignored_directives(opts, &ignored_directives(&params), "configuration file")?;
+        if let Some(origins) = origins.as_mut() {
+            let reader = BufReader::new(
+                File::open(&opts.config).expect("Could not open configuration file"),
+            );
+            let lines = directive_lines(reader, &alias);
+            origins.update(&params, |keyword| {
+                Origin::ConfigFile(lines.get(keyword).cloned())
+            });
+        }
+        // Update options with configuration
+        apply_host_params(opts, &mut preferences, params);
+    }
+    // Layer ssh options from command line on top of the configuration file
+    if !opts.ssh_options.is_empty() {
+        debug!("SSH Options: {:?}", opts.ssh_options);
+        let overrides = format!("Host *\n{}\n", opts.ssh_options.join("\n"));
+        let config = SshConfig::default()
+            .parse(
+                &mut overrides.as_bytes(),
+                ParseRule::ALLOW_UNKNOWN_FIELDS | ParseRule::ALLOW_UNSUPPORTED_FIELDS,
+            )
+            .expect("Failed to parse ssh options");
+        let params = config.query(alias.as_str());
+        check_ignored_directives(opts, &ignored_directives(&params), "ssh options")?;
+        if let Some(origins) = origins.as_mut() {
+            origins.update(&params, |_| Origin::SshOption);
+        }
+        apply_host_params(opts, &mut preferences, params);
+    }
+
+    trace!("Options: {:?}", opts);
+    trace!("Preferences: {:?}", preferences);
+    debug!("User: {}", opts.target.user);
+    if alias != opts.target.host {
+        debug!("Host: {} (alias {alias})", opts.target.host);
+    } else {
+        debug!("Host: {}", opts.target.host);
+    }
+    debug!("Port: {}", opts.target.port);
+    if let Some(origins) = origins {
+        print_config_origins(&origins, opts);
+    }
+
+    // Expand tokens in the remote file path and echo command
+    let port = opts.target.port.to_string();
+    let tokens = [
+        ('h', opts.target.host.as_str()),
+        ('u', opts.target.user.as_str()),
+        ('p', port.as_str()),
+    ];
+    // Use a unique remote file per run unless one is given
+    let generated_remote_file = opts.remote_file.is_none();
+    let remote_file = match &opts.remote_file {
+        Some(remote_file) => match expand_tokens(&remote_file.to_string_lossy(), &tokens) {
+            Ok(remote_file) => PathBuf::from(remote_file),
+            Err(e) => return Err(format!("Invalid remote file: {e}")),
+        },
+        None => unique_remote_file(),
+    };
+    opts.remote_file = Some(remote_file);
+    opts.echo_cmd = match expand_tokens(&opts.echo_cmd, &tokens) {
+        Ok(echo_cmd) => echo_cmd,
+        Err(e) => return Err(format!("Invalid echo command: {e}")),
+    };
+    Ok((preferences, generated_remote_file))
+}
+
+// Tell which directives sshping ignores, refusing them with --strict-config
+fn check_ignored_directives(
+    opts: &Options,
+    directives: &[String],
+    source: &str,
+) -> Result<(), String> {
+    if directives.is_empty() {
+        return Ok(());
+    }
+    let directives = directives.join(", ");
+    if opts.strict_config {
+        return Err(format!("Unsupported directives in {source}: {directives}"));
+    }
+    debug!("Ignoring directives in {source}: {directives}");
+    Ok(())
+}
+
+// Fields for --template, from the target and the results of each test
+fn output_fields(
+    opts: &Options,
+    formatter: &Formatter,
+    connect_time: Option<Duration>,
+    auth_method: Option<&str>,
+    echo: Option<&EchoTestSummary>,
+    speed: Option<&SpeedTestSummary>,
+) -> Vec<(&'static str, String)> {
+    let mut fields = vec![
+        ("host", opts.target.host.clone()),
+        ("user", opts.target.user.clone()),
+        ("port", opts.target.port.to_string()),
+        (
+            "connect_time",
+            connect_time.map_or("-".to_string(), |time| formatter.format_duration(time)),
+        ),
+        ("auth_method", auth_method.unwrap_or("-").to_string()),
+    ];
+    fields.extend(template_fields(echo, speed));
+    fields
+}
+
+// Print metrics of both targets side by side
+fn print_comparison(first: &str, second: &str, comparisons: Vec<Comparison>, opts: &Options) {
+    let mut builder = Builder::default();
+    builder.push_record(["Metric", first, second, "Ratio"]);
+    for comparison in comparisons {
+        builder.push_record([
+            comparison.metric.to_string(),
+            comparison.first,
+            comparison.second,
+            comparison.ratio,
+        ]);
+    }
+    let mut table = builder.build();
+    opts.table_style
+        .stylize(&mut table)
+        .with(Alignment::center())
+        .with(Alignment::center_vertical());
+    emit(&table.to_string());
+}
+
+// Print the resolved settings of the target along with their origins
+fn print_config_origins(origins: &ConfigOrigins, opts: &Options) {
+    let identity = opts
+        .identity
+        .as_ref()
+        .map_or("-".to_string(), |identity| identity.display().to_string());
+    let (proxy_jump, proxy_jump_origin) = match &origins.proxy_jump {
+        Some((proxy_jump, origin)) => (format!("{proxy_jump} (ignored)"), origin.to_string()),
+        None => ("-".to_string(), "-".to_string()),
+    };
+    let mut builder = Builder::default();
+    builder.push_record(["Setting", "Value", "Source"]);
+    builder.push_record([
+        "Host".to_string(),
+        opts.target.host.clone(),
+        origins.host.to_string(),
+    ]);
+    builder.push_record([
+        "User".to_string(),
+        opts.target.user.clone(),
+        origins.user.to_string(),
+    ]);
+    builder.push_record([
+        "Port".to_string(),
+        opts.target.port.to_string(),
+        origins.port.to_string(),
+    ]);
+    builder.push_record([
+        "Identity".to_string(),
+        identity,
+        origins.identity.to_string(),
+    ]);
+    builder.push_record(["ProxyJump".to_string(), proxy_jump, proxy_jump_origin]);
+    let mut table = builder.build();
+    opts.table_style
+        .stylize(&mut table)
+        .with(Alignment::center())
+        .with(Alignment::center_vertical());
+    emit(&table.to_string());
+}
+
+// Print groups of records as a table, merging the test column of each group
+fn print_table(groups: Vec<Vec<Record>>, opts: &Options) {
+    let mut data = vec![];
+    let mut modifications = vec![];
+    for records in groups {
+        modifications.push(((data.len() + 1, 0), Span::row(records.len())));
         data.extend(records);
     }
     let mut table = Table::new(data);
@@ -193,17 +756,5 @@ fn main() -> ExitCode {
         .with(Alignment::center())
         .with(Alignment::center_vertical())
         .with(BorderSpanCorrection);
-    // Clear the line before printing the table
-    print!("{:<80}\r", "");
-    println!("{}", table);
-
-    // Waiting for key input before exiting
-    if opts.key_wait {
-        println!("Press enter to exit...");
-        let mut buf = [0u8; 1];
-        let _ = std::io::stdin().read(&mut buf).unwrap();
-    }
-
-    // Exit successfully
-    ExitCode::SUCCESS
+    emit(&table.to_string());
 }
@@ -0,0 +1,157 @@
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+use log::debug;
+
+use crate::cli::HttpProxy;
+
+// Longest response header accepted from the proxy
+const MAX_RESPONSE_LEN: usize = 8192;
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// Ask the HTTP proxy connected on TCP to open a tunnel to HOST:PORT, after
+// which the stream carries the SSH connection
+pub fn open_tunnel(
+    mut tcp: TcpStream,
+    proxy: &HttpProxy,
+    host: &str,
+    port: u16,
+    timeout: Duration,
+) -> Result<TcpStream, String> {
+    let request = connect_request(proxy, host, port);
+    debug!(
+        "Requesting tunnel to {host}:{port} from {}",
+        proxy.address()
+    );
+    tcp.set_read_timeout(Some(timeout))
+        .map_err(|e| format!("Failed to set proxy timeout: {e}"))?;
+    tcp.write_all(request.as_bytes())
+        .map_err(|e| format!("Failed to send request to proxy: {e}"))?;
+    let response = read_response_head(&mut tcp)?;
+    let status_line = response.lines().next().unwrap_or_default();
+    debug!("Proxy responded with {status_line:?}");
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => {}
+        Some("407") => {
+            return Err(format!(
+                "Proxy requires authentication, give credentials as USER:PASSWORD@{}",
+                proxy.address()
+            ))
+        }
+        _ => return Err(format!("Proxy refused the tunnel: {status_line}")),
+    }
+    tcp.set_read_timeout(None)
+        .map_err(|e| format!("Failed to reset proxy timeout: {e}"))?;
+    Ok(tcp)
+}
+
+// The CONNECT request asking for a tunnel to HOST:PORT
+fn connect_request(proxy: &HttpProxy, host: &str, port: u16) -> String {
+    // IPv6 addresses go in brackets like in URLs
+    let authority = if host.contains(':') {
+        format!("[{host}]:{port}")
+    } else {
+        format!("{host}:{port}")
+    };
+    let mut request = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+    if let Some(credentials) = &proxy.credentials {
+        request += &format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            base64(credentials.as_bytes())
+        );
+    }
+    request + "\r\n"
+}
+
+// Read the response up to the blank line ending its header, one byte at a
+// time so that none of the SSH banner after it is consumed
+fn read_response_head(tcp: &mut TcpStream) -> Result<String, String> {
+    let mut response = vec![];
+    let mut byte = [0; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_RESPONSE_LEN {
+            return Err("Proxy response header is too long".to_string());
+        }
+        match tcp.read(&mut byte) {
+            Ok(0) => return Err("Proxy closed the connection".to_string()),
+            Ok(_) => response.push(byte[0]),
+            Err(e) => return Err(format!("Failed to read proxy response: {e}")),
+        }
+    }
+    Ok(String::from_utf8_lossy(&response).into_owned())
+}
+
+// Standard base64 with padding, as used by Basic authentication
+fn base64(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_test_vectors() {
+        // From RFC 4648, section 10
+        let vectors = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (data, encoded) in vectors {
+            assert_eq!(base64(data.as_bytes()), encoded);
+        }
+    }
+
+    #[test]
+    fn request_with_credentials() {
+        let proxy = HttpProxy {
+            host: "proxy".to_string(),
+            port: 3128,
+            credentials: Some("user:pass".to_string()),
+        };
+        assert_eq!(
+            connect_request(&proxy, "example.com", 22),
+            "CONNECT example.com:22 HTTP/1.1\r\nHost: example.com:22\r\n\
+             Proxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn request_for_ipv6_without_credentials() {
+        let proxy = HttpProxy {
+            host: "proxy".to_string(),
+            port: 3128,
+            credentials: None,
+        };
+        assert_eq!(
+            connect_request(&proxy, "2001:db8::1", 22),
+            "CONNECT [2001:db8::1]:22 HTTP/1.1\r\nHost: [2001:db8::1]:22\r\n\r\n"
+        );
+    }
+}
@@ -0,0 +1,138 @@
+use std::{
+    collections::HashMap,
+    io::{stdout, ErrorKind, IsTerminal, Write},
+    net::{SocketAddr, ToSocketAddrs},
+    process::{self, Child, Stdio},
+    sync::Mutex,
+};
+
+use log::{debug, warn};
+use syslog::Formatter5424;
+
+use crate::{summary::Record, util::shell_command};
+
+// Command reading the results on its stdin instead of stdout, if any
+static PIPE: Mutex<Option<Child>> = Mutex::new(None);
+
+// Waits for the command given by --pipe-to once dropped
+pub struct OutputPipe;
+
+impl Drop for OutputPipe {
+    fn drop(&mut self) {
+        let Some(mut child) = PIPE.lock().unwrap().take() else {
+            return;
+        };
+        // Closing stdin tells the command that no more output follows
+        drop(child.stdin.take());
+        match child.wait() {
+            Ok(status) if status.success() => {}
+            Ok(status) => warn!("Output command exited with {status}"),
+            Err(e) => warn!("Failed to wait for output command: {e}"),
+        }
+    }
+}
+
+// Send all results to the stdin of COMMAND from now on
+pub fn pipe_output_to(command: &str) -> Result<OutputPipe, String> {
+    debug!("Piping output to {command:?}");
+    let child = shell_command(command)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| e.to_string())?;
+    *PIPE.lock().unwrap() = Some(child);
+    Ok(OutputPipe)
+}
+
+// Print a block of results to stdout or to the output command
+pub fn emit(text: &str) {
+    let mut pipe = PIPE.lock().unwrap();
+    let Some(child) = pipe.as_mut() else {
+        // Clear the progress line before printing
+        if stdout().is_terminal() {
+            print!("{:<80}\r", "");
+        }
+        println!("{text}");
+        return;
+    };
+    // Output is dropped once the command stopped reading
+    let Some(stdin) = child.stdin.as_mut() else {
+        return;
+    };
+    if let Err(e) = writeln!(stdin, "{text}") {
+        match e.kind() {
+            ErrorKind::BrokenPipe => warn!("Output command exited before reading all results"),
+            _ => warn!("Failed to write to output command: {e}"),
+        }
+        child.stdin = None;
+    }
+}
+
+// Example enterprise number reserved for documentation by RFC 5612
+const ENTERPRISE_NUMBER: u32 = 32473;
+
+// Structured data names are limited to printable ASCII without separators
+fn sd_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .take(32)
+        .collect()
+}
+
+// Escape characters with special meaning in structured data values
+fn sd_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace(']', "\\]")
+}
+
+// Send all results to syslog as one message, with an element per test,
+// either to the local daemon or to a remote HOST:PORT over UDP
+pub fn send_to_syslog(
+    groups: &[Vec<Record>],
+    host: &str,
+    server: Option<&str>,
+) -> Result<(), String> {
+    let formatter = Formatter5424 {
+        process: "sshping".to_string(),
+        pid: process::id(),
+        ..Default::default()
+    };
+    let mut logger = match server {
+        Some(server) => {
+            let server = server
+                .to_socket_addrs()
+                .map_err(|e| format!("Failed to resolve {server}: {e}"))?
+                .next()
+                .ok_or(format!("No address found for {server}"))?;
+            let local: SocketAddr = match server {
+                SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+                SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+            };
+            debug!("Sending results to syslog at {server}");
+            syslog::udp(formatter, local, server)
+        }
+        None => {
+            debug!("Sending results to local syslog");
+            syslog::unix(formatter)
+        }
+    }
+    .map_err(|e| e.to_string())?;
+    let mut data = HashMap::<String, HashMap<String, String>>::new();
+    for records in groups {
+        for record in records {
+            data.entry(format!("{}@{ENTERPRISE_NUMBER}", sd_name(&record.test)))
+                .or_default()
+                .insert(sd_name(&record.metric), sd_value(&record.result));
+        }
+    }
+    logger
+        .info((1, data, format!("sshping results for {host}")))
+        .map_err(|e| e.to_string())
+}
@@ -1,60 +1,229 @@
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use tabled::Tabled;
 
 use crate::util::Formatter;
 
+// Percentile of sorted samples, linearly interpolated between closest ranks
+fn percentile(sorted: &[u128], p: f64) -> f64 {
+    let rank = p / 100.0 * (sorted.len() - 1) as f64;
+    let (lower, upper) = (rank.floor() as usize, rank.ceil() as usize);
+    let weight = rank - lower as f64;
+    sorted[lower] as f64 * (1.0 - weight) + sorted[upper] as f64 * weight
+}
+
+// Mean, standard deviation and median of sorted samples
+fn central_tendency(sorted: &[u128]) -> (u128, u128, u128) {
+    let count = sorted.len();
+    let mean = sorted.iter().sum::<u128>() / (count as u128);
+    let std = ((sorted
+        .iter()
+        .map(|&latency| ((latency as i128) - (mean as i128)).pow(2))
+        .sum::<i128>() as f64)
+        / (count as f64))
+        .sqrt() as u128;
+    let median = match count % 2 {
+        0 => (sorted[count / 2 - 1] + sorted[count / 2]) / 2,
+        _ => sorted[count / 2],
+    };
+    (mean, std, median)
+}
+
+// Number of samples falling in each range between sorted boundaries,
+// labelled like "<= 1ms", "1ms - 5ms" and "> 5ms"
+fn bucket_counts(sorted: &[u128], boundaries: &[Duration]) -> Vec<(String, usize)> {
+    let mut boundaries = boundaries.to_vec();
+    boundaries.sort();
+    boundaries.dedup();
+    let mut buckets = Vec::with_capacity(boundaries.len() + 1);
+    let mut lower = None;
+    let mut counted = 0;
+    for boundary in boundaries {
+        let upper = humantime::format_duration(boundary);
+        let end = sorted.partition_point(|&latency| latency <= boundary.as_nanos());
+        let label = match lower {
+            Some(lower) => format!("{lower} - {upper}"),
+            None => format!("<= {upper}"),
+        };
+        buckets.push((label, end - counted));
+        counted = end;
+        lower = Some(upper);
+    }
+    if let Some(lower) = lower {
+        buckets.push((format!("> {lower}"), sorted.len() - counted));
+    }
+    buckets
+}
+
+// Sorted samples left after cutting PCT percent, rounded down, off each
+// end, or None if nothing is left
+fn trim(sorted: &[u128], pct: f64) -> Option<&[u128]> {
+    let cut = (sorted.len() as f64 * pct / 100.0) as usize;
+    sorted
+        .get(cut..sorted.len().saturating_sub(cut))
+        .filter(|kept| !kept.is_empty())
+}
+
+// Statistics after discarding outliers on both ends
+pub struct TrimmedLatency {
+    pub pct: f64,
+    pub avg_latency: String,
+    pub std_latency: String,
+    pub med_latency: String,
+}
+
 pub struct EchoTestSummary {
     pub char_sent: usize,
+    // Raw mean latency in nanoseconds, kept for comparisons
+    pub avg_nanos: u128,
     pub avg_latency: String,
     pub std_latency: String,
     pub med_latency: String,
     pub min_latency: String,
     pub max_latency: String,
+    // Requested percentiles along with their formatted latency
+    pub percentiles: Vec<(f64, String)>,
+    pub trimmed: Option<TrimmedLatency>,
+    // Latency ranges along with their formatted count and share
+    pub buckets: Vec<(String, String)>,
 }
 
 impl EchoTestSummary {
-    pub fn from_latencies(latencies: &Vec<u128>, formatter: &Formatter) -> Self {
+    pub fn from_latencies(
+        latencies: &Vec<u128>,
+        percentiles: &[f64],
+        trim_outliers: Option<f64>,
+        buckets: &[Duration],
+        formatter: &Formatter,
+    ) -> Self {
         let char_sent = latencies.len();
-        let avg_latency = latencies.iter().sum::<u128>() / (char_sent as u128);
-        let std_latency = formatter.format_duration(Duration::from_nanos(
-            ((latencies
-                .iter()
-                .map(|&latency| ((latency as i128) - (avg_latency as i128)).pow(2))
-                .sum::<i128>() as f64)
-                / (char_sent as f64))
-                .sqrt() as u64,
-        ));
-        let avg_latency = formatter.format_duration(Duration::from_nanos(avg_latency as u64));
-        let med_latency = formatter.format_duration(Duration::from_nanos(
-            (match char_sent % 2 {
-                0 => (latencies[char_sent / 2 - 1] + latencies[char_sent / 2]) / 2,
-                _ => latencies[char_sent / 2],
-            }) as u64,
-        ));
+        let format =
+            |latency: u128| formatter.format_duration(Duration::from_nanos(latency as u64));
+        let (avg_nanos, std_latency, med_latency) = central_tendency(latencies);
+        let (avg_latency, std_latency, med_latency) =
+            (format(avg_nanos), format(std_latency), format(med_latency));
+        let trimmed = trim_outliers.and_then(|pct| {
+            let kept = trim(latencies, pct)?;
+            let (avg_latency, std_latency, med_latency) = central_tendency(kept);
+            Some(TrimmedLatency {
+                pct,
+                avg_latency: format(avg_latency),
+                std_latency: format(std_latency),
+                med_latency: format(med_latency),
+            })
+        });
         let min_latency = formatter.format_duration(Duration::from_nanos(
             latencies.first().unwrap().to_owned() as u64,
         ));
         let max_latency = formatter.format_duration(Duration::from_nanos(
             latencies.last().unwrap().to_owned() as u64,
         ));
+        let percentiles = percentiles
+            .iter()
+            .map(|&p| {
+                let latency = percentile(latencies, p);
+                (
+                    p,
+                    formatter.format_duration(Duration::from_nanos(latency as u64)),
+                )
+            })
+            .collect();
+        let buckets = bucket_counts(latencies, buckets)
+            .into_iter()
+            .map(|(label, count)| {
+                let share = count as f64 / char_sent as f64 * 100.0;
+                (label, format!("{count} ({share:.1}%)"))
+            })
+            .collect();
         Self {
             char_sent,
+            avg_nanos,
             avg_latency,
             std_latency,
             med_latency,
             min_latency,
             max_latency,
+            percentiles,
+            trimmed,
+            buckets,
         }
     }
     pub fn to_formatted_frame(&self) -> Vec<Record> {
+        self.to_records("Latency")
+    }
+
+    pub fn to_records(&self, test: &'static str) -> Vec<Record> {
         vec![
-            Record::new("Latency", "Average", self.avg_latency.clone()),
-            Record::new("Latency", "Std deviation", self.std_latency.clone()),
-            Record::new("Latency", "Median", self.med_latency.clone()),
-            Record::new("Latency", "Minimum", self.min_latency.clone()),
-            Record::new("Latency", "Maximum", self.max_latency.clone()),
+            Record::new(test, "Average", self.avg_latency.clone()),
+            Record::new(test, "Std deviation", self.std_latency.clone()),
+            Record::new(test, "Median", self.med_latency.clone()),
+            Record::new(test, "Minimum", self.min_latency.clone()),
+            Record::new(test, "Maximum", self.max_latency.clone()),
         ]
+        .into_iter()
+        .chain(
+            self.percentiles
+                .iter()
+                .map(|(p, latency)| Record::new(test, format!("P{p}"), latency.clone())),
+        )
+        .chain(self.trimmed.iter().flat_map(|trimmed| {
+            let pct = trimmed.pct;
+            [
+                Record::new(
+                    test,
+                    format!("Average ({pct}% trimmed)"),
+                    trimmed.avg_latency.clone(),
+                ),
+                Record::new(
+                    test,
+                    format!("Std deviation ({pct}% trimmed)"),
+                    trimmed.std_latency.clone(),
+                ),
+                Record::new(
+                    test,
+                    format!("Median ({pct}% trimmed)"),
+                    trimmed.med_latency.clone(),
+                ),
+            ]
+        }))
+        .chain(
+            self.buckets
+                .iter()
+                .map(|(label, count)| Record::new(test, label.clone(), count.clone())),
+        )
+        .collect()
+    }
+}
+
+pub struct ConnectTestSummary {
+    pub count: usize,
+    pub rate: String,
+    // Statistics of connection setup time, from TCP connect to authenticated
+    pub setup: EchoTestSummary,
+}
+
+impl ConnectTestSummary {
+    pub fn new(
+        setup_times: &Vec<u128>,
+        elapsed: Duration,
+        percentiles: &[f64],
+        formatter: &Formatter,
+    ) -> Self {
+        let count = setup_times.len();
+        Self {
+            count,
+            rate: format!("{:.2}/s", count as f64 / elapsed.as_secs_f64()),
+            setup: EchoTestSummary::from_latencies(setup_times, percentiles, None, &[], formatter),
+        }
+    }
+
+    pub fn to_formatted_frame(&self) -> Vec<Record> {
+        let mut records = vec![
+            Record::new("Connect", "Connections", self.count.to_string()),
+            Record::new("Connect", "Rate", self.rate.clone()),
+        ];
+        records.extend(self.setup.to_records("Connect"));
+        records
     }
 }
 
@@ -62,48 +231,449 @@ pub struct SpeedTestResult {
     pub size: String,
     pub time: String,
     pub speed: String,
+    // Raw throughput in bytes per second, kept for comparisons
+    pub bytes_per_sec: f64,
+    // When the transfer started and how long it took
+    pub started_at: Instant,
+    pub elapsed: Duration,
+    // Requested percentiles of the throughput over each sampling interval
+    pub rate_percentiles: Vec<(f64, String)>,
 }
 
 impl SpeedTestResult {
-    pub fn new(size: u64, time: Duration, formatter: &Formatter) -> Self {
+    pub fn new(size: u64, started_at: Instant, time: Duration, formatter: &Formatter) -> Self {
+        let bytes_per_sec = (size as f64) / time.as_secs_f64();
         Self {
             size: formatter.format_size(size),
             time: formatter.format_duration(time),
-            speed: formatter.format_size(((size as f64) / time.as_secs_f64()) as u64) + "/s",
+            speed: formatter.format_rate(bytes_per_sec),
+            bytes_per_sec,
+            started_at,
+            elapsed: time,
+            rate_percentiles: vec![],
         }
     }
+
+    pub fn set_rate_percentiles(
+        &mut self,
+        rates: Vec<f64>,
+        percentiles: &[f64],
+        formatter: &Formatter,
+    ) {
+        if rates.is_empty() {
+            return;
+        }
+        let mut rates = rates
+            .into_iter()
+            .map(|rate| rate as u128)
+            .collect::<Vec<_>>();
+        rates.sort();
+        self.rate_percentiles = percentiles
+            .iter()
+            .map(|&p| {
+                let rate = percentile(&rates, p);
+                (p, formatter.format_rate(rate))
+            })
+            .collect();
+    }
 }
 
-pub struct SpeedTestSummary {
+// Channel data carried by each SSH packet, as sent by libssh2
+const SSH_PACKET_PAYLOAD: f64 = 32768.0;
+// Length, padding length, typical padding, MAC or tag and channel data header
+const SSH_PACKET_OVERHEAD: f64 = 38.0;
+// Data carried by each SFTP read or write request, as sent by libssh2
+const SFTP_CHUNK: f64 = 30000.0;
+// Length, type, request id, handle, offset and data length
+const SFTP_REQUEST_OVERHEAD: f64 = 33.0;
+// Typical TCP segment payload, with IPv4 and TCP headers including timestamps
+const TCP_MSS: f64 = 1448.0;
+const TCP_IP_OVERHEAD: f64 = 52.0;
+
+// Estimated bytes on the wire for each byte of payload, counting SSH packet
+// framing, SFTP requests if used and TCP/IP headers
+pub fn wire_bytes_ratio(sftp: bool) -> f64 {
+    let sftp_ratio = if sftp {
+        1.0 + SFTP_REQUEST_OVERHEAD / SFTP_CHUNK
+    } else {
+        1.0
+    };
+    sftp_ratio
+        * (1.0 + SSH_PACKET_OVERHEAD / SSH_PACKET_PAYLOAD)
+        * (1.0 + TCP_IP_OVERHEAD / TCP_MSS)
+}
+
+// Goodput next to the estimated throughput on the wire
+pub struct WireEstimate {
+    pub upload: Option<String>,
+    pub download: String,
+    // Share of the wire throughput taken by protocol overhead
+    pub upload_overhead: Option<f64>,
+    pub download_overhead: f64,
+}
+
+impl WireEstimate {
+    pub fn new(
+        upload: Option<(&SpeedTestResult, bool)>,
+        download: (&SpeedTestResult, bool),
+        formatter: &Formatter,
+    ) -> Self {
+        let wire_speed = |(result, sftp): (&SpeedTestResult, bool)| {
+            formatter.format_rate(result.bytes_per_sec * wire_bytes_ratio(sftp))
+        };
+        let overhead = |(_, sftp): (&SpeedTestResult, bool)| 1.0 - 1.0 / wire_bytes_ratio(sftp);
+        Self {
+            upload: upload.map(wire_speed),
+            download: wire_speed(download),
+            upload_overhead: upload.map(overhead),
+            download_overhead: overhead(download),
+        }
+    }
+}
+
+// File-backed throughput below this fraction of the in-memory one is
+// considered to be limited by the remote disk
+const DISK_BOUND_RATIO: f64 = 0.75;
+
+pub struct SpeedTestBaseline {
     pub upload: SpeedTestResult,
     pub download: SpeedTestResult,
 }
 
+// Median echo latency while transferring, if any echo was probed
+pub struct LoadedLatency {
+    pub upload: Option<String>,
+    pub download: Option<String>,
+}
+
+pub struct SpeedTestSummary {
+    // Upload result, absent when an existing remote file was reused
+    pub upload: Option<SpeedTestResult>,
+    pub download: SpeedTestResult,
+    // In-memory transfer results, only present when diagnosing bottleneck
+    pub baseline: Option<SpeedTestBaseline>,
+    // Latency under load, only present when probing during transfer
+    pub loaded_latency: Option<LoadedLatency>,
+    // Estimated wire throughput, only present when showing overhead
+    pub wire: Option<WireEstimate>,
+}
+
 impl SpeedTestSummary {
+    pub fn upload_disk_bound(&self) -> Option<bool> {
+        let upload = self.upload.as_ref()?;
+        self.baseline
+            .as_ref()
+            .map(|baseline| upload.bytes_per_sec < baseline.upload.bytes_per_sec * DISK_BOUND_RATIO)
+    }
+
+    pub fn download_disk_bound(&self) -> Option<bool> {
+        self.baseline.as_ref().map(|baseline| {
+            self.download.bytes_per_sec < baseline.download.bytes_per_sec * DISK_BOUND_RATIO
+        })
+    }
+
+    // How many times faster the quicker direction is, if both were run
+    pub fn asymmetry_ratio(&self) -> Option<f64> {
+        let upload = self.upload.as_ref()?.bytes_per_sec;
+        let download = self.download.bytes_per_sec;
+        Some(upload.max(download) / upload.min(download))
+    }
+
     pub fn to_formatted_frame(&self) -> Vec<Record> {
-        vec![
-            Record::new("Speed", "Upload", self.upload.speed.clone()),
+        let mut records = vec![
+            Record::new(
+                "Speed",
+                "Upload",
+                self.upload
+                    .as_ref()
+                    .map_or("Skipped".to_string(), |upload| upload.speed.clone()),
+            ),
             Record::new("Speed", "Download", self.download.speed.clone()),
-        ]
+        ];
+        let results = [
+            ("Upload", self.upload.as_ref()),
+            ("Download", Some(&self.download)),
+        ];
+        for (name, result) in results
+            .into_iter()
+            .filter_map(|(name, result)| Some((name, result?)))
+        {
+            records.extend(
+                result
+                    .rate_percentiles
+                    .iter()
+                    .map(|(p, rate)| Record::new("Speed", format!("{name} P{p}"), rate.clone())),
+            );
+        }
+        if let Some(loaded_latency) = &self.loaded_latency {
+            let not_available = || "N/A".to_string();
+            records.push(Record::new(
+                "Speed",
+                "Upload latency",
+                loaded_latency.upload.clone().unwrap_or_else(not_available),
+            ));
+            records.push(Record::new(
+                "Speed",
+                "Download latency",
+                loaded_latency
+                    .download
+                    .clone()
+                    .unwrap_or_else(not_available),
+            ));
+        }
+        if let Some(wire) = &self.wire {
+            if let (Some(speed), Some(overhead)) = (&wire.upload, wire.upload_overhead) {
+                records.push(Record::new("Speed", "Upload (wire, est.)", speed.clone()));
+                records.push(Record::new(
+                    "Speed",
+                    "Upload overhead (est.)",
+                    format!("{:.1}%", overhead * 100.0),
+                ));
+            }
+            records.push(Record::new(
+                "Speed",
+                "Download (wire, est.)",
+                wire.download.clone(),
+            ));
+            records.push(Record::new(
+                "Speed",
+                "Download overhead (est.)",
+                format!("{:.1}%", wire.download_overhead * 100.0),
+            ));
+        }
+        if let Some(baseline) = &self.baseline {
+            records.push(Record::new(
+                "Speed",
+                "Upload (memory)",
+                baseline.upload.speed.clone(),
+            ));
+            records.push(Record::new(
+                "Speed",
+                "Download (memory)",
+                baseline.download.speed.clone(),
+            ));
+            let bottleneck = match (self.upload_disk_bound(), self.download_disk_bound()) {
+                (Some(true), Some(true)) => "Disk (upload, download)",
+                (Some(true), _) => "Disk (upload)",
+                (_, Some(true)) => "Disk (download)",
+                _ => "Network",
+            };
+            records.push(Record::new("Speed", "Bottleneck", bottleneck.to_string()));
+        }
+        records
+    }
+}
+
+// Results of the selected tests against one target
+pub struct TargetSummary {
+    pub connect_time: Duration,
+    pub echo: Option<EchoTestSummary>,
+    pub speed: Option<SpeedTestSummary>,
+}
+
+// One metric measured on both targets of a comparison
+pub struct Comparison {
+    pub metric: &'static str,
+    pub first: String,
+    pub second: String,
+    // Second result relative to the first
+    pub ratio: String,
+}
+
+impl Comparison {
+    fn new(metric: &'static str, first: f64, second: f64, format: impl Fn(f64) -> String) -> Self {
+        let ratio = if first > 0.0 {
+            format!("{:.2}x", second / first)
+        } else {
+            "N/A".to_string()
+        };
+        Self {
+            metric,
+            first: format(first),
+            second: format(second),
+            ratio,
+        }
+    }
+
+    // Compare every metric both targets have a result for
+    pub fn between(
+        first: &TargetSummary,
+        second: &TargetSummary,
+        formatter: &Formatter,
+    ) -> Vec<Self> {
+        let duration = |nanos: f64| formatter.format_duration(Duration::from_nanos(nanos as u64));
+        let speed = |bytes_per_sec: f64| formatter.format_rate(bytes_per_sec);
+        let mut comparisons = vec![Self::new(
+            "Connect time",
+            first.connect_time.as_nanos() as f64,
+            second.connect_time.as_nanos() as f64,
+            duration,
+        )];
+        if let (Some(a), Some(b)) = (&first.echo, &second.echo) {
+            comparisons.push(Self::new(
+                "Average latency",
+                a.avg_nanos as f64,
+                b.avg_nanos as f64,
+                duration,
+            ));
+        }
+        if let (Some(a), Some(b)) = (&first.speed, &second.speed) {
+            if let (Some(a), Some(b)) = (&a.upload, &b.upload) {
+                comparisons.push(Self::new("Upload", a.bytes_per_sec, b.bytes_per_sec, speed));
+            }
+            comparisons.push(Self::new(
+                "Download",
+                a.download.bytes_per_sec,
+                b.download.bytes_per_sec,
+                speed,
+            ));
+        }
+        comparisons
     }
 }
 
+// Result fields available to --template, with "-" for tests that were not run
+pub fn template_fields(
+    echo: Option<&EchoTestSummary>,
+    speed: Option<&SpeedTestSummary>,
+) -> Vec<(&'static str, String)> {
+    let field = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+    let upload = speed.and_then(|s| s.upload.as_ref());
+    vec![
+        ("chars_sent", field(echo.map(|e| e.char_sent.to_string()))),
+        ("latency_avg", field(echo.map(|e| e.avg_latency.clone()))),
+        ("latency_std", field(echo.map(|e| e.std_latency.clone()))),
+        ("latency_med", field(echo.map(|e| e.med_latency.clone()))),
+        ("latency_min", field(echo.map(|e| e.min_latency.clone()))),
+        ("latency_max", field(echo.map(|e| e.max_latency.clone()))),
+        ("upload_size", field(upload.map(|u| u.size.clone()))),
+        ("upload_time", field(upload.map(|u| u.time.clone()))),
+        ("upload_speed", field(upload.map(|u| u.speed.clone()))),
+        (
+            "download_size",
+            field(speed.map(|s| s.download.size.clone())),
+        ),
+        (
+            "download_time",
+            field(speed.map(|s| s.download.time.clone())),
+        ),
+        (
+            "download_speed",
+            field(speed.map(|s| s.download.speed.clone())),
+        ),
+        (
+            "asymmetry_ratio",
+            field(
+                speed
+                    .and_then(SpeedTestSummary::asymmetry_ratio)
+                    .map(|ratio| format!("{ratio:.2}")),
+            ),
+        ),
+    ]
+}
+
 #[derive(Tabled)]
 pub struct Record {
     #[tabled(rename = "Test")]
-    pub test: &'static str,
+    pub test: String,
     #[tabled(rename = "Metric")]
-    pub metric: &'static str,
+    pub metric: String,
     #[tabled(rename = "Result")]
     pub result: String,
 }
 
 impl Record {
-    pub fn new(test: &'static str, metric: &'static str, result: String) -> Self {
+    pub fn new(test: impl Into<String>, metric: impl Into<String>, result: String) -> Self {
         Self {
-            test,
-            metric,
+            test: test.into(),
+            metric: metric.into(),
             result,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentile_extremes() {
+        let sorted = [10, 20, 30, 40];
+        assert_eq!(percentile(&sorted, 0.0), 10.0);
+        assert_eq!(percentile(&sorted, 100.0), 40.0);
+    }
+
+    #[test]
+    fn percentile_median() {
+        // Interpolated between the middle samples of an even count
+        assert_eq!(percentile(&[10, 20, 30, 40], 50.0), 25.0);
+        assert_eq!(percentile(&[10, 20, 30], 50.0), 20.0);
+    }
+
+    #[test]
+    fn percentile_fractional() {
+        let sorted = (1..=1000).collect::<Vec<u128>>();
+        // Rank 998.001 lies just past the 999th sample
+        assert!((percentile(&sorted, 99.9) - 999.001).abs() < 1e-6);
+        assert_eq!(percentile(&[10, 20], 25.0), 12.5);
+    }
+
+    #[test]
+    fn percentile_single_sample() {
+        assert_eq!(percentile(&[7], 0.0), 7.0);
+        assert_eq!(percentile(&[7], 99.9), 7.0);
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn buckets_include_their_upper_boundary() {
+        let sorted = [500_000, 1_000_000, 1_000_001, 5_000_000, 9_000_000];
+        assert_eq!(
+            bucket_counts(&sorted, &[ms(1), ms(5)]),
+            [
+                ("<= 1ms".to_string(), 2),
+                ("1ms - 5ms".to_string(), 2),
+                ("> 5ms".to_string(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn bucket_boundaries_are_sorted_and_deduplicated() {
+        let sorted = [500_000, 2_000_000, 9_000_000];
+        assert_eq!(
+            bucket_counts(&sorted, &[ms(5), ms(1), ms(5)]),
+            bucket_counts(&sorted, &[ms(1), ms(5)])
+        );
+    }
+
+    #[test]
+    fn buckets_without_boundaries_or_samples() {
+        assert!(bucket_counts(&[1_000_000], &[]).is_empty());
+        assert_eq!(
+            bucket_counts(&[], &[ms(1)]),
+            [("<= 1ms".to_string(), 0), ("> 1ms".to_string(), 0)]
+        );
+    }
+
+    #[test]
+    fn trim_rounds_cut_down() {
+        let sorted = (1..=10).collect::<Vec<u128>>();
+        assert_eq!(trim(&sorted, 10.0), Some(&sorted[1..9]));
+        // 1.9 samples off each end is rounded down to 1
+        assert_eq!(trim(&sorted, 19.0), Some(&sorted[1..9]));
+        assert_eq!(trim(&sorted, 0.0), Some(&sorted[..]));
+    }
+
+    #[test]
+    fn trim_leaving_nothing() {
+        assert_eq!(trim(&[1, 2], 49.0), Some(&[1, 2][..]));
+        assert_eq!(trim(&[1, 2, 3, 4], 49.0), Some(&[2, 3][..]));
+        assert_eq!(trim(&[1, 2, 3], 49.9), Some(&[2][..]));
+        assert_eq!(trim(&[1, 2], 50.0), None);
+        assert_eq!(trim(&[], 10.0), None);
+    }
+}
@@ -1,59 +1,192 @@
 use std::{
-    io::{Read, Write},
-    path::PathBuf,
-    time::{Duration, Instant},
+    fmt::Display,
+    io::{Error, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process,
+    thread::sleep,
+    time::{Duration, Instant, SystemTime},
 };
 
-use indicatif::{ProgressBar, ProgressState, ProgressStyle};
+use indicatif::ProgressBar;
 use log::{debug, info, log_enabled, trace, warn, Level};
 use rand::{
     distributions::{Distribution, Uniform},
-    thread_rng,
+    random, thread_rng, Rng,
 };
-use ssh2::Session;
+use ssh2::{Channel, ErrorCode, File, FileStat, MethodType, Session};
 
 use crate::{
-    summary::{EchoTestSummary, SpeedTestResult, SpeedTestSummary},
-    util::Formatter,
+    cli::{DownloadSource, Options, Protocol, Test},
+    hdr::write_hdr_output,
+    summary::{
+        EchoTestSummary, LoadedLatency, Record, SpeedTestBaseline, SpeedTestResult,
+        SpeedTestSummary, WireEstimate,
+    },
+    util::{new_progress_bar, push_progress_summary, shell_quote, Formatter},
 };
 
-fn get_progress_bar_style(test_name: &str) -> ProgressStyle {
-    ProgressStyle::default_bar()
-        .template(
-            &format!(
-                "{name} {{spinner:.green}} [{{elapsed_precise}}] [{{wide_bar:.cyan/blue}}] {{bytes}}/{{total_bytes}} ({{eta}})",
-                name = test_name
-            )
-        )
-        .unwrap()
-        .with_key("eta", |state: &ProgressState, w: &mut dyn std::fmt::Write|
-            write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap()
-        )
-        .progress_chars("#>-")
-}
+// libssh2 error when the server refuses to open a channel
+const LIBSSH2_ERROR_CHANNEL_FAILURE: i32 = -21;
+// libssh2 errors worth retrying an SFTP call for
+const LIBSSH2_ERROR_TIMEOUT: i32 = -9;
+const LIBSSH2_ERROR_EAGAIN: i32 = -37;
+// Echoes sent to measure the round trip time for --auto-chunk-size
+const RTT_PROBES: usize = 10;
+// Bandwidth in bytes per second assumed for the bandwidth-delay product
+const NOMINAL_BANDWIDTH: f64 = 125e6;
+const MIN_AUTO_CHUNK_SIZE: u64 = 32 * 1024;
+const MAX_AUTO_CHUNK_SIZE: u64 = 16 * 1024 * 1024;
 
-pub fn run_echo_test(
-    session: &Session,
-    echo_cmd: &str,
-    char_count: usize,
-    time_limit: Option<f64>,
+// Size of the line buffer of a terminal in canonical mode on Linux
+const PTY_LINE_LIMIT: u64 = 4096;
+
+// Describe a failed transfer, telling timeouts apart from other errors
+fn transfer_error(
+    e: Error,
+    action: &str,
+    transferred: u64,
+    total: u64,
     formatter: &Formatter,
-) -> Result<EchoTestSummary, String> {
-    info!("Running echo latency test");
-    debug!("Running echo test with command: {echo_cmd:?}");
-    debug!("Number of characters to echo: {char_count:?}");
-    debug!("Time limit for echo: {time_limit:?} seconds");
+) -> String {
+    let progress = format!(
+        "{action} {} of {}",
+        formatter.format_size(transferred),
+        formatter.format_size(total)
+    );
+    match e.kind() {
+        ErrorKind::TimedOut => format!("Timed out after {progress}"),
+        _ => format!("{e} (after {progress})"),
+    }
+}
+
+fn generate_random_data(size: u64) -> String {
+    let dist = Uniform::from(0..128 as u8);
+    dist.sample_iter(thread_rng())
+        .take(size as usize)
+        .map(|v| ((v & 0x3f) + 32) as char)
+        .collect::<String>()
+}
+
+// Servers refuse channels beyond their MaxSessions or MaxStartups limits
+fn is_channel_refused(e: &ssh2::Error) -> bool {
+    e.code() == ErrorCode::Session(LIBSSH2_ERROR_CHANNEL_FAILURE)
+}
+
+// Describe a failure to open a channel, pointing at server limits if refused
+fn channel_error(e: ssh2::Error) -> String {
+    if is_channel_refused(&e) {
+        format!("Server refused channel: may have hit MaxSessions ({e})")
+    } else {
+        e.to_string()
+    }
+}
+
+// Open a channel, retrying with backoff while the server refuses it
+fn open_with_retries<T>(
+    retries: usize,
+    mut open: impl FnMut() -> Result<T, ssh2::Error>,
+) -> Result<T, ssh2::Error> {
+    let mut attempt = 0;
+    loop {
+        match open() {
+            Err(e) if attempt < retries && is_channel_refused(&e) => {
+                attempt += 1;
+                warn!("Server refused channel, retrying ({attempt}/{retries})");
+                sleep(Duration::from_millis(500 << (attempt - 1)));
+            }
+            result => return result,
+        }
+    }
+}
+
+// SFTP calls that timed out on a loaded server rather than failed for good.
+// The generic SFTP failure status is not retried, as servers also use it for
+// a full disk, a read-only file system or a directory in the way
+fn is_transient_sftp_error(e: &ssh2::Error) -> bool {
+    matches!(
+        e.code(),
+        ErrorCode::Session(LIBSSH2_ERROR_TIMEOUT | LIBSSH2_ERROR_EAGAIN)
+    )
+}
+
+// Same for SFTP reads and writes, which report errors through std::io
+fn is_transient_io_error(e: &Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::TimedOut | ErrorKind::WouldBlock | ErrorKind::Interrupted
+    )
+}
+
+// Retry an SFTP call with backoff while it fails transiently, passing the
+// attempt number so that reads and writes can seek back first
+fn retry_sftp<T, E: Display>(
+    retries: usize,
+    name: &str,
+    is_transient: fn(&E) -> bool,
+    mut call: impl FnMut(usize) -> Result<T, E>,
+) -> Result<T, E> {
+    let mut attempt = 0;
+    loop {
+        match call(attempt) {
+            Err(e) if attempt < retries && is_transient(&e) => {
+                attempt += 1;
+                warn!("SFTP {name} failed: {e}, retrying ({attempt}/{retries})");
+                sleep(Duration::from_millis(200 << (attempt - 1)));
+            }
+            result => return result,
+        }
+    }
+}
+
+// Ask the server to set the variables of --setenv and SetEnv on the channel,
+// which it may refuse for any not listed in its AcceptEnv
+fn send_env(channel: &mut Channel, opts: &Options) {
+    for (key, value) in &opts.setenv {
+        trace!("Setting remote environment variable {key}");
+        if let Err(e) = channel.setenv(key, value) {
+            warn!("Server refused to set environment variable {key}: {e}");
+        }
+    }
+}
+
+// Turns off bracketed paste and line editing in bash and zsh, so that
+// readline redraws do not interleave with the echo. Errors are discarded as
+// other shells do not know these commands
+const ECHO_SHELL_SETUP: &str = "bind 'set enable-bracketed-paste off' 2>/dev/null; \
+    set +o emacs +o vi 2>/dev/null; printf '\\033[?2004l'\n";
+
+// Start the echo command in an interactive shell, or run it directly
+fn start_echo_channel(session: &Session, opts: &Options) -> Result<Channel, String> {
+    let echo_cmd = &opts.echo_cmd;
     // Start the channel server
     trace!("Preparing channel session");
-    let mut channel = session.channel_session().map_err(|e| e.to_string())?;
+    let mut channel = session.channel_session().map_err(channel_error)?;
+    send_env(&mut channel, opts);
+    // The program's own stdout is the echo, no terminal involved
+    if opts.echo_exec {
+        trace!("Executing echo program");
+        channel.exec(echo_cmd).map_err(|e| e.to_string())?;
+        return Ok(channel);
+    }
     // Request a pseudo-terminal for the interactive shell
+    let (cols, rows) = opts.term_size;
+    trace!(
+        "Requesting {cols}x{rows} pseudo-terminal of type {:?}",
+        opts.term
+    );
     channel
-        .request_pty("sshping", None, Some((10, 5, 0, 0)))
+        .request_pty(&opts.term, None, Some((cols, rows, 0, 0)))
         .map_err(|e| e.to_string())?;
     channel.shell().map_err(|e| e.to_string())?;
+    // Quiet the shell before the echo command unless asked to see it all
+    let setup = if opts.no_echo_filter {
+        ""
+    } else {
+        ECHO_SHELL_SETUP
+    };
     // Send the echo command to accept input
     trace!("Starting echo command");
-    let echo_cmd = format!("{echo_cmd}\n");
+    let echo_cmd = format!("{setup}{echo_cmd}\n");
     channel
         .write_all(echo_cmd.as_bytes())
         .map_err(|e| e.to_string())?;
@@ -61,42 +194,290 @@ pub fn run_echo_test(
     // Read the initial buffer to clear the echo command
     let mut buffer = [0; 1500];
     channel.read(&mut buffer).map_err(|e| e.to_string())?;
+    Ok(channel)
+}
+
+// Retry the echo setup on transient failures, never the measurement itself
+fn start_echo_channel_with_retries(session: &Session, opts: &Options) -> Result<Channel, String> {
+    let retries = opts.echo_setup_retries;
+    let mut attempt = 0;
+    loop {
+        match start_echo_channel(session, opts) {
+            Ok(channel) => return Ok(channel),
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                warn!("Failed to set up echo shell: {e}, retrying ({attempt}/{retries})");
+                sleep(Duration::from_millis(500 * attempt as u64));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Send a single character and wait for its echo, returning the latency in ns
+//
+// With filter enabled, any other output from the shell (prompts, escape
+// sequences, etc.) is skipped until the character itself is echoed back
+fn echo_round_trip(channel: &mut Channel, ch: &[u8], filter: bool) -> Result<u128, String> {
+    let start = Instant::now();
+    channel.write_all(ch).map_err(|e| e.to_string())?;
+    read_echo(channel, ch, filter).map_err(|e| e.to_string())?;
+    Ok(start.elapsed().as_nanos())
+}
+
+// Read up to and including the echo of CH, or just one byte without filter
+fn read_echo(reader: &mut impl Read, ch: &[u8], filter: bool) -> Result<(), Error> {
+    let mut read_buffer = [0; 1];
+    loop {
+        reader.read_exact(&mut read_buffer)?;
+        if !filter || read_buffer == ch {
+            return Ok(());
+        }
+        trace!("Discarding shell output {:?}", read_buffer[0] as char);
+    }
+}
+
+// Send a payload of several bytes and wait until as many bytes are echoed
+// back, returning the latency in ns
+fn echo_payload_round_trip(channel: &mut Channel, payload: &[u8]) -> Result<u128, String> {
+    let mut read_buffer = vec![0; payload.len()];
+    let mut received = 0;
+    let start = Instant::now();
+    channel.write_all(payload).map_err(|e| e.to_string())?;
+    while received < payload.len() {
+        received += channel
+            .read(&mut read_buffer[received..])
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(start.elapsed().as_nanos())
+}
+
+// Allowed disagreement between the monotonic and wall clocks, covering small
+// adjustments of the wall clock
+const CLOCK_TOLERANCE: Duration = Duration::from_secs(1);
+
+// Whether a duration measured with the monotonic clock is implausible, being
+// either zero or much longer than the wall clock time since `wall_start`.
+// Only catches gross errors when `wall_start` lies well before the
+// measurement, so take it right before what is measured
+fn is_timer_anomaly(measured: Duration, wall_start: SystemTime) -> bool {
+    if measured.is_zero() {
+        return true;
+    }
+    // A wall clock stepped backwards leaves nothing to compare against
+    match wall_start.elapsed() {
+        Ok(wall) => measured > wall * 2 + CLOCK_TOLERANCE,
+        Err(_) => false,
+    }
+}
+
+// Flag a transfer whose measured time cannot be trusted
+fn check_transfer_timing(name: &str, result: &SpeedTestResult, wall_start: SystemTime) {
+    if is_timer_anomaly(result.elapsed, wall_start) {
+        warn!(
+            "{name} took an implausible {}, the system timer may be unreliable",
+            result.time
+        );
+    }
+}
+
+// Echo channel probed in between chunks of the speed test
+struct LoadProbe {
+    channel: Channel,
+    latencies: Vec<u128>,
+}
+
+impl LoadProbe {
+    fn new(session: &Session, opts: &Options) -> Result<Self, String> {
+        Ok(Self {
+            channel: start_echo_channel_with_retries(session, opts)?,
+            latencies: vec![],
+        })
+    }
+
+    fn probe(&mut self) -> Result<(), String> {
+        let latency = echo_round_trip(&mut self.channel, b"x", true)?;
+        self.latencies.push(latency);
+        Ok(())
+    }
+
+    // Median of the latencies collected so far, resetting for the next transfer
+    fn take_median(&mut self) -> Option<Duration> {
+        let mut latencies = std::mem::take(&mut self.latencies);
+        latencies.sort();
+        latencies
+            .get(latencies.len() / 2)
+            .map(|&latency| Duration::from_nanos(latency as u64))
+    }
+}
+
+// Echoes collected before --adaptive-echo may stop, and how often it checks
+const ADAPTIVE_MIN_SAMPLES: usize = 30;
+const ADAPTIVE_CHECK_INTERVAL: usize = 10;
+
+// Running mean and variance of the latencies, to tell when they converge
+#[derive(Default)]
+struct Convergence {
+    count: usize,
+    mean: f64,
+    m2: f64,
+}
+
+impl Convergence {
+    fn add(&mut self, latency: u128) {
+        self.count += 1;
+        let delta = latency as f64 - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (latency as f64 - self.mean);
+    }
+
+    // Whether the 95% confidence interval of the mean lies within the given
+    // fraction of it
+    fn is_stable(&self, precision: f64) -> bool {
+        if self.count < ADAPTIVE_MIN_SAMPLES || !self.count.is_multiple_of(ADAPTIVE_CHECK_INTERVAL)
+        {
+            return false;
+        }
+        let std = (self.m2 / (self.count - 1) as f64).sqrt();
+        1.96 * std / (self.count as f64).sqrt() <= precision * self.mean
+    }
+}
+
+pub fn run_echo_test(
+    session: &Session,
+    opts: &Options,
+    formatter: &Formatter,
+) -> Result<EchoTestSummary, String> {
+    let echo_cmd = &opts.echo_cmd;
+    let char_count = opts.char_count;
+    let time_limit = opts.echo_timeout;
+    let echo_duration = opts.echo_duration;
+    let echo_filter = !opts.no_echo_filter;
+    info!("Running echo latency test");
+    debug!("Running echo test with command: {echo_cmd:?}");
+    match echo_duration {
+        Some(duration) => debug!("Echoing characters for {duration:?}"),
+        None => debug!("Number of characters to echo: {char_count:?}"),
+    }
+    debug!("Time limit for echo: {time_limit:?} seconds");
+    debug!("Filtering shell output: {echo_filter}");
+    let mut channel = start_echo_channel_with_retries(session, opts)?;
 
     // Prepare the echo test
     trace!("Testing echo latency");
     let write_buffer = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
-    let mut read_buffer = [0; 1];
+    // Compression would shrink the repeating alphabet, so pick each
+    // character at random instead
+    let compressed = session
+        .methods(MethodType::CompCs)
+        .is_some_and(|method| method != "none");
+    let random_payload = opts.random_echo_payload || compressed;
+    if random_payload {
+        debug!("Sending random characters (compression: {compressed})");
+    }
+    let mut rng = thread_rng();
     let mut latencies = Vec::with_capacity(char_count);
     let timeout = time_limit.map(|time| Duration::from_secs_f64(time));
+    // Echo until the time is up rather than for a number of characters
+    let count = match echo_duration {
+        Some(_) => usize::MAX,
+        None => char_count,
+    };
+    let interval = opts.echo_interval.map(Duration::from_millis);
     let start_time = Instant::now();
-    let progress_bar = ProgressBar::new(char_count as u64);
-    progress_bar.set_style(get_progress_bar_style("Echo test"));
+    let mut anomalies = 0;
+    let mut convergence = Convergence::default();
+    let progress_bar = match echo_duration {
+        Some(duration) => new_progress_bar(duration.as_millis() as u64, "Echo test", false),
+        None => new_progress_bar(char_count as u64, "Echo test", false),
+    };
 
-    for (n, idx) in (0..char_count).zip((0..write_buffer.len()).cycle()) {
-        let start = Instant::now();
-        channel
-            .write_all(&write_buffer[idx..idx + 1])
-            .map_err(|e| e.to_string())?;
-        channel
-            .read_exact(&mut read_buffer)
-            .map_err(|e| e.to_string())?;
-        let latency = start.elapsed().as_nanos();
-        latencies.push(latency);
+    for (n, idx) in (0..count).zip((0..write_buffer.len()).cycle()) {
+        let idx = if random_payload {
+            rng.gen_range(0..write_buffer.len())
+        } else {
+            idx
+        };
+        // Each echo is checked against the wall clock over its own round trip
+        let wall_start = SystemTime::now();
+        let latency = match echo_round_trip(&mut channel, &write_buffer[idx..idx + 1], echo_filter)
+        {
+            Ok(latency) => latency,
+            // Some echo programs exit on their own, e.g. after a fixed input
+            Err(_) if channel.eof() && opts.echo_test_ignore_exit => {
+                info!("Echo command exited after {} echoes", latencies.len());
+                break;
+            }
+            Err(_) if channel.eof() => {
+                return Err(
+                    "Echo command exited during the test, use --echo-test-ignore-exit to keep the echoes so far"
+                        .to_string(),
+                );
+            }
+            Err(e) => return Err(e),
+        };
+        // Keep timer glitches out of the statistics
+        if is_timer_anomaly(Duration::from_nanos(latency as u64), wall_start) {
+            trace!("Discarding implausible latency of {latency} ns");
+            anomalies += 1;
+        } else {
+            latencies.push(latency);
+            convergence.add(latency);
+        }
+        if let Some(precision) = opts.adaptive_echo {
+            if convergence.is_stable(precision) {
+                info!("Latency converged after {} echoes", latencies.len());
+                break;
+            }
+        }
         if let Some(timeout) = timeout {
             if start_time.elapsed() > timeout {
                 break;
             }
         }
-        progress_bar.set_position((n as u64) + 1);
+        match echo_duration {
+            Some(duration) => {
+                if start_time.elapsed() >= duration {
+                    info!("Echoed {} characters in {duration:?}", latencies.len());
+                    break;
+                }
+                progress_bar.set_position(start_time.elapsed().as_millis() as u64);
+            }
+            None => progress_bar.set_position((n as u64) + 1),
+        }
+        // Pace the characters outside of the measured round trip
+        if let Some(interval) = interval {
+            sleep(interval);
+        }
     }
     progress_bar.finish_and_clear();
+    if anomalies > 0 {
+        warn!("Discarded {anomalies} echo samples with implausible timing, the system timer may be unreliable");
+    }
+
+    // The statistics need at least one echo, e.g. the echo command may have
+    // exited before answering
+    if latencies.is_empty() && anomalies > 0 {
+        return Err(
+            "Every echo had implausible timing, the system timer is unreliable".to_string(),
+        );
+    }
+    if latencies.is_empty() {
+        return Err("Unable to get any echos in given time".to_string());
+    }
 
     // Calculate latency statistics
     latencies.sort();
-    let result = EchoTestSummary::from_latencies(&latencies, formatter);
-    if result.char_sent == 0 {
-        return Err("Unable to get any echos in given time".to_string());
+    if let Some(path) = &opts.hdr_output {
+        write_hdr_output(path, &latencies)?;
     }
+    let result = EchoTestSummary::from_latencies(
+        &latencies,
+        &opts.percentiles,
+        opts.trim_outliers,
+        &opts.latency_buckets,
+        formatter,
+    );
     if result.char_sent < 20 {
         warn!("Insufficient data points for accurate latency measurement");
     }
@@ -127,8 +508,9 @@ pub fn run_echo_test(
                 .to_owned() as u64,
         );
         info!(
-            "Sent {}/{char_count}, Latency:\n\tMean:\t{}\n\tStd:\t{}\n\tMin:\t{}\n\tMedian:\t{}\n\tMax:\t{}\n\t1% High:\t{}\n\t5% High:\t{}\n\t10% High:\t{}",
+            "Sent {}/{}, Latency:\n\tMean:\t{}\n\tStd:\t{}\n\tMin:\t{}\n\tMedian:\t{}\n\tMax:\t{}\n\t1% High:\t{}\n\t5% High:\t{}\n\t10% High:\t{}",
             result.char_sent,
+            echo_duration.map_or(char_count.to_string(), |duration| format!("{duration:?}")),
             result.avg_latency,
             result.std_latency,
             result.min_latency,
@@ -144,43 +526,100 @@ pub fn run_echo_test(
 
 fn run_upload_test(
     session: &Session,
-    size: u64,
-    chunk_size: u64,
+    opts: &Options,
     remote_file: &PathBuf,
+    size: u64,
+    probe: &mut Option<LoadProbe>,
     formatter: &Formatter,
 ) -> Result<SpeedTestResult, String> {
-    info!("Running upload speed test");
+    let (chunk_size, protocol) = (opts.chunk_size, opts.protocol);
+    info!("Running upload speed test over {protocol:?}");
+    // Refuse to start if the remote disk obviously cannot hold the file
+    if let Some(available) = remote_free_space(session, remote_file) {
+        if available < size && opts.speed_duration.is_none() {
+            return Err(format!(
+                "Remote disk has only {} free for {remote_file:?}, but {} is needed",
+                formatter.format_size(available),
+                formatter.format_size(size)
+            ));
+        }
+    }
     // Prepare the upload test
-    trace!("Establishing SCP channel");
-    let mut channel = session
-        .scp_send(&remote_file, 0o644, size, None)
-        .map_err(|e| e.to_string())?;
+    let mut writer = RemoteWriter::create(session, remote_file, size, opts)?;
     // Generate random data to upload
     trace!("Generating random data");
-    let dist = Uniform::from(0..128 as u8);
-    let buffer = dist
-        .sample_iter(thread_rng())
-        .take(size as usize)
-        .map(|v| ((v & 0x3f) + 32) as char)
-        .collect::<String>();
+    // Time bound transfers send the same data over and over
+    let buffer = generate_random_data(size.min(opts.size));
     // Preparing logging variables
     let mut total_bytes_sent = 0;
-    let start_time: Instant = Instant::now();
-    let progress_bar = ProgressBar::new(size);
-    progress_bar.set_style(get_progress_bar_style("Upload test"));
+    let mut clock = TransferClock::start(opts);
+    let progress_bar = clock.progress_bar(size, "Upload test");
 
     // Starting uploading file
     trace!("Sending file in chunks");
-    for chunk in buffer.as_bytes().chunks(chunk_size as usize) {
-        channel.write_all(chunk).map_err(|e| e.to_string())?;
+    for chunk in buffer.as_bytes().chunks(chunk_size as usize).cycle() {
+        let chunk = &chunk[..chunk.len().min((size - total_bytes_sent as u64) as usize)];
+        if let Err(e) = writer.write_all(chunk, total_bytes_sent as u64, opts.sftp_retries) {
+            // SFTP reports a full disk as soon as a write fails
+            if is_disk_full(session, remote_file, chunk_size) {
+                return Err(disk_full(
+                    session,
+                    remote_file,
+                    total_bytes_sent as u64,
+                    size,
+                    formatter,
+                ));
+            }
+            return Err(transfer_error(
+                e,
+                "sending",
+                total_bytes_sent as u64,
+                size,
+                formatter,
+            ));
+        }
         total_bytes_sent += chunk.len();
-        progress_bar.set_position(total_bytes_sent as u64);
+        clock.show_progress(&progress_bar, total_bytes_sent as u64);
+        clock.update(total_bytes_sent as u64);
+        if let Some(probe) = probe.as_mut() {
+            probe.probe()?;
+        }
+        if total_bytes_sent as u64 >= size || clock.is_over() {
+            break;
+        }
     }
     progress_bar.finish_and_clear();
-    // Clean up the channel
-    channel.send_eof().map_err(|e| e.to_string())?;
+    // Count flushing the file to the remote disk as part of the upload
+    if opts.fsync {
+        let sync_start = Instant::now();
+        writer.fsync()?;
+        debug!(
+            "Remote file synced in {}",
+            formatter.format_duration(sync_start.elapsed())
+        );
+    }
+    let elapsed = clock.elapsed();
+    // SCP only reports write failures once the file is complete
+    if let Err(e) = writer.finish() {
+        if is_disk_full(session, remote_file, chunk_size) {
+            return Err(disk_full(
+                session,
+                remote_file,
+                total_bytes_sent as u64,
+                size,
+                formatter,
+            ));
+        }
+        return Err(e);
+    }
 
-    let result = SpeedTestResult::new(total_bytes_sent as u64, start_time.elapsed(), formatter);
+    let mut result = SpeedTestResult::new(
+        clock.measured(total_bytes_sent as u64),
+        clock.start_time,
+        elapsed,
+        formatter,
+    );
+    result.set_rate_percentiles(clock.rates(), &opts.throughput_percentiles, formatter);
     info!(
         "Sent {}, Time Elapsed: {}, Average Speed: {}",
         result.size, result.time, result.speed
@@ -189,73 +628,1011 @@ fn run_upload_test(
     Ok(result)
 }
 
+// Throughput of each fixed interval during a transfer
+struct RateSampler {
+    interval: Duration,
+    last_time: Instant,
+    last_bytes: u64,
+    rates: Vec<f64>,
+}
+
+impl RateSampler {
+    fn new(start_time: Instant, interval: f64) -> Self {
+        Self {
+            interval: Duration::from_secs_f64(interval),
+            last_time: start_time,
+            last_bytes: 0,
+            rates: vec![],
+        }
+    }
+
+    fn update(&mut self, bytes: u64) {
+        let elapsed = self.last_time.elapsed();
+        if elapsed >= self.interval {
+            self.rates
+                .push((bytes - self.last_bytes) as f64 / elapsed.as_secs_f64());
+            self.last_time = Instant::now();
+            self.last_bytes = bytes;
+        }
+    }
+
+    fn rates(self) -> Vec<f64> {
+        self.rates
+    }
+}
+
+// Clock of a transfer, restarted once the first --speed-warmup-bytes are
+// through so that slow start is left out of the throughput
+struct TransferClock {
+    start_time: Instant,
+    // Bytes transferred before the clock was restarted
+    offset: u64,
+    warmup: u64,
+    interval: f64,
+    sampler: RateSampler,
+    // Stop the transfer after this long under --speed-duration
+    duration: Option<Duration>,
+}
+
+impl TransferClock {
+    fn start(opts: &Options) -> Self {
+        let start_time = Instant::now();
+        Self {
+            start_time,
+            offset: 0,
+            warmup: opts.speed_warmup_bytes,
+            interval: opts.throughput_interval,
+            sampler: RateSampler::new(start_time, opts.throughput_interval),
+            duration: opts.speed_duration,
+        }
+    }
+
+    // Bar counting bytes, or milliseconds when the transfer is time bound
+    fn progress_bar(&self, size: u64, name: &str) -> ProgressBar {
+        match self.duration {
+            Some(duration) => new_progress_bar(duration.as_millis() as u64, name, false),
+            None => new_progress_bar(size, name, true),
+        }
+    }
+
+    fn show_progress(&self, progress_bar: &ProgressBar, total: u64) {
+        match self.duration {
+            Some(_) => progress_bar.set_position(self.elapsed().as_millis() as u64),
+            None => progress_bar.set_position(total),
+        }
+    }
+
+    fn is_over(&self) -> bool {
+        self.duration
+            .is_some_and(|duration| self.elapsed() >= duration)
+    }
+
+    fn update(&mut self, total: u64) {
+        if self.warmup > 0 && total >= self.warmup {
+            trace!("Warmup of {total} bytes done, restarting the clock");
+            self.warmup = 0;
+            self.start_time = Instant::now();
+            self.offset = total;
+            self.sampler = RateSampler::new(self.start_time, self.interval);
+            return;
+        }
+        self.sampler.update(total - self.offset);
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.start_time.elapsed()
+    }
+
+    // Bytes transferred since the clock was last started
+    fn measured(&self, total: u64) -> u64 {
+        total - self.offset
+    }
+
+    fn rates(self) -> Vec<f64> {
+        self.sampler.rates()
+    }
+}
+
+// Remote end of an upload over either protocol
+enum RemoteWriter {
+    Scp(Channel),
+    Sftp(File),
+}
+
+impl RemoteWriter {
+    fn create(
+        session: &Session,
+        remote_file: &Path,
+        size: u64,
+        opts: &Options,
+    ) -> Result<Self, String> {
+        let retries = opts.channel_retries;
+        match opts.protocol {
+            Protocol::Scp => {
+                trace!("Establishing SCP channel");
+                let channel =
+                    open_with_retries(retries, || session.scp_send(remote_file, 0o644, size, None))
+                        .map_err(channel_error)?;
+                Ok(Self::Scp(channel))
+            }
+            Protocol::Sftp => {
+                trace!("Opening SFTP file");
+                let sftp = open_with_retries(retries, || session.sftp()).map_err(channel_error)?;
+                let mut file =
+                    retry_sftp(opts.sftp_retries, "create", is_transient_sftp_error, |_| {
+                        sftp.create(remote_file)
+                    })
+                    .map_err(|e| e.to_string())?;
+                if opts.preallocate {
+                    trace!("Setting remote file size to {size}");
+                    let stat = FileStat {
+                        size: Some(size),
+                        uid: None,
+                        gid: None,
+                        perm: None,
+                        atime: None,
+                        mtime: None,
+                    };
+                    retry_sftp(
+                        opts.sftp_retries,
+                        "setstat",
+                        is_transient_sftp_error,
+                        |_| file.setstat(stat.clone()),
+                    )
+                    .map_err(|e| format!("Failed to preallocate remote file: {e}"))?;
+                }
+                Ok(Self::Sftp(file))
+            }
+        }
+    }
+
+    // Write the chunk starting at offset, retrying transient SFTP failures
+    fn write_all(&mut self, buf: &[u8], offset: u64, retries: usize) -> std::io::Result<()> {
+        match self {
+            Self::Scp(channel) => channel.write_all(buf),
+            Self::Sftp(file) => retry_sftp(retries, "write", is_transient_io_error, |attempt| {
+                if attempt > 0 {
+                    file.seek(SeekFrom::Start(offset))?;
+                }
+                file.write_all(buf)
+            }),
+        }
+    }
+
+    fn fsync(&mut self) -> Result<(), String> {
+        match self {
+            Self::Scp(_) => Err("Syncing the remote file requires SFTP".to_string()),
+            Self::Sftp(file) => file.fsync().map_err(|e| {
+                format!("Failed to sync remote file, server may lack fsync@openssh.com: {e}")
+            }),
+        }
+    }
+
+    // Close the remote file, returning the error the remote side reported if any
+    fn finish(self) -> Result<(), String> {
+        match self {
+            Self::Scp(mut channel) => {
+                channel.send_eof().map_err(|e| e.to_string())?;
+                finish_scp_upload(&mut channel)
+            }
+            Self::Sftp(mut file) => file.close().map_err(|e| e.to_string()),
+        }
+    }
+}
+
+// Remote end of a file download over either protocol
+enum RemoteReader {
+    Scp(Channel),
+    Sftp(File),
+}
+
+impl RemoteReader {
+    // Read a chunk starting at offset, retrying transient SFTP failures
+    fn read_exact(&mut self, buf: &mut [u8], offset: u64, retries: usize) -> std::io::Result<()> {
+        match self {
+            Self::Scp(channel) => channel.read_exact(buf),
+            Self::Sftp(file) => retry_sftp(retries, "read", is_transient_io_error, |attempt| {
+                if attempt > 0 {
+                    file.seek(SeekFrom::Start(offset))?;
+                }
+                file.read_exact(buf)
+            }),
+        }
+    }
+}
+
+// Whether a failed upload ran out of space, judged by what is left on the
+// remote disk since servers like OpenSSH's sftp-server report a full disk as
+// a generic failure
+fn is_disk_full(session: &Session, remote_file: &Path, chunk_size: u64) -> bool {
+    remote_free_space(session, remote_file).is_some_and(|available| available < chunk_size)
+}
+
+// Remove the partial file and describe how far the upload got
+fn disk_full(
+    session: &Session,
+    remote_file: &Path,
+    sent: u64,
+    size: u64,
+    formatter: &Formatter,
+) -> String {
+    if let Err(e) = remove_remote_file(session, remote_file) {
+        warn!("Failed to remove partial remote file {remote_file:?}: {e}");
+    }
+    format!(
+        "Remote disk full while writing {remote_file:?}, wrote {} of {}",
+        formatter.format_size(sent),
+        formatter.format_size(size)
+    )
+}
+
+// Wait for the remote scp to exit, returning the error it reported if any
+fn finish_scp_upload(channel: &mut Channel) -> Result<(), String> {
+    channel.wait_eof().map_err(|e| e.to_string())?;
+    let mut output = Vec::new();
+    channel
+        .read_to_end(&mut output)
+        .map_err(|e| e.to_string())?;
+    channel
+        .stderr()
+        .read_to_end(&mut output)
+        .map_err(|e| e.to_string())?;
+    channel.wait_close().map_err(|e| e.to_string())?;
+    match channel.exit_status().map_err(|e| e.to_string())? {
+        0 => Ok(()),
+        code => {
+            // Strip the scp protocol status bytes around the message
+            let output = String::from_utf8_lossy(&output);
+            let message = output.trim_matches(|c: char| c.is_control() || c.is_whitespace());
+            if message.is_empty() {
+                Err(format!("Remote scp exited with status {code}"))
+            } else {
+                Err(message.to_string())
+            }
+        }
+    }
+}
+
+// Available space in bytes on the remote file system holding the file,
+// or None if it cannot be determined
+fn remote_free_space(session: &Session, remote_file: &Path) -> Option<u64> {
+    let directory = remote_file.parent()?;
+    let mut channel = session.channel_session().ok()?;
+    channel
+        .exec(&format!(
+            "df -Pk {}",
+            shell_quote(&directory.to_string_lossy())
+        ))
+        .ok()?;
+    let mut output = String::new();
+    channel.read_to_string(&mut output).ok()?;
+    channel.wait_close().ok()?;
+    // Second line of POSIX output is "FS 1024-blocks Used Available ..."
+    let available = output
+        .lines()
+        .nth(1)?
+        .split_whitespace()
+        .nth(3)?
+        .parse::<u64>()
+        .ok()?;
+    debug!("Remote free space: {} KiB", available);
+    Some(available * 1024)
+}
+
 fn run_download_test(
     session: &Session,
-    chunk_size: u64,
+    opts: &Options,
     remote_file: &PathBuf,
+    limit: u64,
+    probe: &mut Option<LoadProbe>,
     formatter: &Formatter,
 ) -> Result<SpeedTestResult, String> {
-    info!("Running download speed test");
-    // Prepare the upload test
-    trace!("Establishing SCP channel");
-    let (mut channel, stat) = session.scp_recv(&remote_file).map_err(|e| e.to_string())?;
-    let size = stat.size();
+    let (chunk_size, protocol) = (opts.chunk_size, opts.protocol);
+    info!("Running download speed test over {protocol:?}");
+    // Prepare the download test
+    let metadata_error = |e: ssh2::Error| match e {
+        e if is_channel_refused(&e) => channel_error(e),
+        e => match Error::from(e) {
+            e if e.kind() == ErrorKind::TimedOut => {
+                "Timed out while waiting for remote file metadata".to_string()
+            }
+            e => e.to_string(),
+        },
+    };
+    let retries = opts.sftp_retries;
+    let (mut channel, size) = match protocol {
+        Protocol::Scp => {
+            trace!("Establishing SCP channel");
+            let (channel, stat) =
+                open_with_retries(opts.channel_retries, || session.scp_recv(remote_file))
+                    .map_err(metadata_error)?;
+            (RemoteReader::Scp(channel), stat.size())
+        }
+        Protocol::Sftp => {
+            trace!("Opening SFTP file");
+            let sftp = open_with_retries(opts.channel_retries, || session.sftp())
+                .map_err(channel_error)?;
+            let mut file = retry_sftp(retries, "open", is_transient_sftp_error, |_| {
+                sftp.open(remote_file)
+            })
+            .map_err(|e| e.to_string())?;
+            let stat = retry_sftp(retries, "stat", is_transient_sftp_error, |_| file.stat())
+                .map_err(metadata_error)?;
+            (RemoteReader::Sftp(file), stat.size.unwrap_or_default())
+        }
+    };
     if size == 0 {
         return Err("Remote file is empty".to_string());
     }
+    // Stop early rather than read past the data budget
+    let size = size.min(limit);
     // Prepare buffer for downloading
     trace!("Preparing buffer for downloading");
     let mut buffer = vec![0; chunk_size as usize];
     // Preparing logging variables
     let mut total_bytes_recv = 0;
-    let start_time: Instant = Instant::now();
-    let progress_bar = ProgressBar::new(size);
-    progress_bar.set_style(get_progress_bar_style("Download test"));
+    let mut clock = TransferClock::start(opts);
+    let progress_bar = clock.progress_bar(size, "Download test");
 
     // Starting downloading file
     trace!("Receiving file in chunks");
-    while size - total_bytes_recv > chunk_size {
-        channel.read_exact(&mut buffer).map_err(|e| e.to_string())?;
-        total_bytes_recv += chunk_size;
-        progress_bar.set_position(total_bytes_recv as u64);
+    while size > total_bytes_recv && !clock.is_over() {
+        let read_size = (size - total_bytes_recv).min(chunk_size);
+        channel
+            .read_exact(&mut buffer[..read_size as usize], total_bytes_recv, retries)
+            .map_err(|e| transfer_error(e, "receiving", total_bytes_recv, size, formatter))?;
+        total_bytes_recv += read_size;
+        clock.show_progress(&progress_bar, total_bytes_recv);
+        clock.update(total_bytes_recv);
+        if let Some(probe) = probe.as_mut() {
+            probe.probe()?;
+        }
     }
-    if size - total_bytes_recv > 0 {
-        total_bytes_recv += channel
-            .read_to_end(&mut buffer)
-            .map_err(|e| e.to_string())? as u64;
-        progress_bar.set_position(total_bytes_recv as u64);
+    progress_bar.finish_and_clear();
+    // Clean up the channel
+    drop(channel);
+
+    let mut result = SpeedTestResult::new(
+        clock.measured(total_bytes_recv),
+        clock.start_time,
+        clock.elapsed(),
+        formatter,
+    );
+    result.set_rate_percentiles(clock.rates(), &opts.throughput_percentiles, formatter);
+    info!(
+        "Received {}, Time Elapsed: {}, Average Speed: {}",
+        result.size, result.time, result.speed
+    );
+
+    Ok(result)
+}
+
+fn run_memory_upload_test(
+    session: &Session,
+    opts: &Options,
+    size: u64,
+    formatter: &Formatter,
+) -> Result<SpeedTestResult, String> {
+    let chunk_size = opts.chunk_size;
+    info!("Running in-memory upload speed test");
+    // Prepare the upload test, discarding everything on the remote side
+    trace!("Establishing exec channel");
+    let mut channel = open_with_retries(opts.channel_retries, || session.channel_session())
+        .map_err(channel_error)?;
+    channel.exec("cat > /dev/null").map_err(|e| e.to_string())?;
+    // Generate random data to upload
+    trace!("Generating random data");
+    let buffer = generate_random_data(size.min(opts.size));
+    // Preparing logging variables
+    let mut total_bytes_sent = 0;
+    let mut clock = TransferClock::start(opts);
+    let progress_bar = clock.progress_bar(size, "Upload test (memory)");
+
+    // Starting uploading data
+    trace!("Sending data in chunks");
+    for chunk in buffer.as_bytes().chunks(chunk_size as usize).cycle() {
+        let chunk = &chunk[..chunk.len().min((size - total_bytes_sent as u64) as usize)];
+        channel
+            .write_all(chunk)
+            .map_err(|e| transfer_error(e, "sending", total_bytes_sent as u64, size, formatter))?;
+        total_bytes_sent += chunk.len();
+        clock.show_progress(&progress_bar, total_bytes_sent as u64);
+        clock.update(total_bytes_sent as u64);
+        if total_bytes_sent as u64 >= size || clock.is_over() {
+            break;
+        }
     }
     progress_bar.finish_and_clear();
+    let elapsed = clock.elapsed();
     // Clean up the channel
     channel.send_eof().map_err(|e| e.to_string())?;
+    channel.wait_close().map_err(|e| e.to_string())?;
 
-    let result = SpeedTestResult::new(total_bytes_recv as u64, start_time.elapsed(), formatter);
+    let result = SpeedTestResult::new(
+        clock.measured(total_bytes_sent as u64),
+        clock.start_time,
+        elapsed,
+        formatter,
+    );
     info!(
-        "Received {}, Time Elapsed: {}, Average Speed: {}",
+        "Sent {} to memory, Time Elapsed: {}, Average Speed: {}",
         result.size, result.time, result.speed
     );
 
     Ok(result)
 }
 
-pub fn run_speed_test(
+fn run_device_download_test(
     session: &Session,
+    opts: &Options,
+    device: &str,
     size: u64,
-    chunk_size: u64,
-    remote_file: &PathBuf,
+    test_name: &str,
+    probe: &mut Option<LoadProbe>,
+    formatter: &Formatter,
+) -> Result<SpeedTestResult, String> {
+    let chunk_size = opts.chunk_size;
+    info!("Running download speed test from {device}");
+    // Prepare the download test, reading from a device instead of a file
+    trace!("Establishing exec channel");
+    let mut channel = open_with_retries(opts.channel_retries, || session.channel_session())
+        .map_err(channel_error)?;
+    let command = if size == u64::MAX {
+        format!("cat {device}")
+    } else {
+        format!("head -c {size} {device}")
+    };
+    channel.exec(&command).map_err(|e| e.to_string())?;
+    // Prepare buffer for downloading
+    trace!("Preparing buffer for downloading");
+    let mut buffer = vec![0; chunk_size as usize];
+    // Preparing logging variables
+    let mut total_bytes_recv = 0;
+    let mut next_probe = chunk_size;
+    let mut clock = TransferClock::start(opts);
+    let progress_bar = clock.progress_bar(size, test_name);
+
+    // Starting downloading data until the remote command finishes
+    trace!("Receiving data in chunks");
+    loop {
+        let read = channel
+            .read(&mut buffer)
+            .map_err(|e| transfer_error(e, "receiving", total_bytes_recv, size, formatter))?;
+        if read == 0 {
+            break;
+        }
+        total_bytes_recv += read as u64;
+        clock.show_progress(&progress_bar, total_bytes_recv);
+        clock.update(total_bytes_recv);
+        if let Some(probe) = probe.as_mut() {
+            if total_bytes_recv >= next_probe {
+                probe.probe()?;
+                next_probe += chunk_size;
+            }
+        }
+        if clock.is_over() {
+            break;
+        }
+    }
+    progress_bar.finish_and_clear();
+    let elapsed = clock.elapsed();
+    // Clean up the channel, stopping the remote command if time ran out
+    channel.close().map_err(|e| e.to_string())?;
+    channel.wait_close().map_err(|e| e.to_string())?;
+    if total_bytes_recv == 0 {
+        return Err(format!("Received no data from {device}"));
+    }
+
+    let mut result = SpeedTestResult::new(
+        clock.measured(total_bytes_recv),
+        clock.start_time,
+        elapsed,
+        formatter,
+    );
+    result.set_rate_percentiles(clock.rates(), &opts.throughput_percentiles, formatter);
+    info!(
+        "Received {} from {device}, Time Elapsed: {}, Average Speed: {}",
+        result.size, result.time, result.speed
+    );
+
+    Ok(result)
+}
+
+// Bytes the speed test may still transfer under --data-budget
+struct DataBudget(Option<u64>);
+
+impl DataBudget {
+    // Largest transfer of up to `wanted` bytes that leaves an equal share of
+    // the budget for each of the `parts` transfers still to run
+    fn allow(&self, wanted: u64, parts: u64) -> u64 {
+        self.0
+            .map_or(wanted, |remaining| wanted.min(remaining / parts))
+    }
+
+    fn spend(&mut self, bytes: u64) {
+        if let Some(remaining) = self.0.as_mut() {
+            *remaining = remaining.saturating_sub(bytes);
+        }
+    }
+}
+
+// Warn when the data budget cuts a transfer short
+fn warn_budget_limit(name: &str, allowed: u64, opts: &Options, formatter: &Formatter) {
+    let wanted = opts.size;
+    if opts.speed_duration.is_none() && allowed < wanted {
+        warn!(
+            "Data budget limits the {name} to {} instead of {}",
+            formatter.format_size(allowed),
+            formatter.format_size(wanted)
+        );
+    }
+}
+
+pub fn run_speed_test(
+    session: &Session,
+    opts: &Options,
     formatter: &Formatter,
 ) -> Result<SpeedTestSummary, String> {
+    let size = opts.size;
+    let remote_file = opts
+        .remote_file
+        .as_ref()
+        .expect("Remote file should be resolved before the speed test");
+    let download_source = opts.download_source;
     info!("Running speed test");
     debug!(
         "Running speed test with file size: {}",
         formatter.format_size(size)
     );
     debug!("Remote file path: {remote_file:?}");
+    debug!("Download source: {download_source:?}");
 
-    let upload_result = run_upload_test(session, size, chunk_size, remote_file, formatter)?;
-    let download_result = run_download_test(session, chunk_size, remote_file, formatter)?;
-    Ok(SpeedTestSummary {
+    // Probe latency with echos while transferring if requested
+    let mut probe = if opts.loaded_latency {
+        Some(LoadProbe::new(session, opts)?)
+    } else {
+        None
+    };
+    // Skip the upload if a previous run left a file of the right size
+    let reuse = opts.reuse_remote_file && {
+        let existing = remote_file_size(session, remote_file);
+        match existing {
+            Some(existing) if existing == size => true,
+            Some(existing) => {
+                info!(
+                    "Remote file has {} instead of {}, uploading it again",
+                    formatter.format_size(existing),
+                    formatter.format_size(size)
+                );
+                false
+            }
+            None => false,
+        }
+    };
+    let mut budget = DataBudget(opts.data_budget);
+    // Time bound transfers only stop early for the data budget
+    let wanted = match opts.speed_duration {
+        Some(_) => u64::MAX,
+        None => size,
+    };
+    let upload_result = if reuse {
+        info!("Reusing remote file {remote_file:?}, skipping upload test");
+        None
+    } else {
+        // Leave at least as much for downloading the file again
+        let upload_size = budget.allow(wanted, 2);
+        if upload_size == 0 {
+            return Err("Data budget is too small for the speed test".to_string());
+        }
+        warn_budget_limit("upload", upload_size, opts, formatter);
+        let wall_start = SystemTime::now();
+        let result = run_upload_test(
+            session,
+            opts,
+            remote_file,
+            upload_size,
+            &mut probe,
+            formatter,
+        )?;
+        budget.spend(upload_size);
+        check_transfer_timing("Upload", &result, wall_start);
+        push_progress_summary(format!("upload: {}", result.speed));
+        Some(result)
+    };
+    let upload_latency = probe.as_mut().and_then(LoadProbe::take_median);
+    let download_size = budget.allow(wanted, 1);
+    if download_size == 0 {
+        return Err("Data budget is too small for the speed test".to_string());
+    }
+    warn_budget_limit("download", download_size, opts, formatter);
+    let wall_start = SystemTime::now();
+    let download_result = match download_source {
+        DownloadSource::File => run_download_test(
+            session,
+            opts,
+            remote_file,
+            download_size,
+            &mut probe,
+            formatter,
+        )?,
+        DownloadSource::Urandom => run_device_download_test(
+            session,
+            opts,
+            "/dev/urandom",
+            download_size,
+            "Download test",
+            &mut probe,
+            formatter,
+        )?,
+        DownloadSource::Zero => run_device_download_test(
+            session,
+            opts,
+            "/dev/zero",
+            download_size,
+            "Download test",
+            &mut probe,
+            formatter,
+        )?,
+    };
+    budget.spend(download_size);
+    check_transfer_timing("Download", &download_result, wall_start);
+    push_progress_summary(format!("download: {}", download_result.speed));
+    let download_latency = probe.as_mut().and_then(LoadProbe::take_median);
+    let loaded_latency = probe.map(|_| LoadedLatency {
+        upload: upload_latency.map(|latency| formatter.format_duration(latency)),
+        download: download_latency.map(|latency| formatter.format_duration(latency)),
+    });
+    // Compare against transfers that never touch the remote disk
+    let baseline_size = budget.allow(wanted, 2);
+    let baseline = if !opts.diagnose_bottleneck {
+        None
+    } else if baseline_size == 0 {
+        warn!("Data budget is used up, skipping the in-memory transfers");
+        None
+    } else {
+        warn_budget_limit("in-memory transfers", baseline_size, opts, formatter);
+        Some(SpeedTestBaseline {
+            upload: run_memory_upload_test(session, opts, baseline_size, formatter)?,
+            download: run_device_download_test(
+                session,
+                opts,
+                "/dev/zero",
+                baseline_size,
+                "Download test (memory)",
+                &mut None,
+                formatter,
+            )?,
+        })
+    };
+    // Only transfers of the remote file go through SFTP requests
+    let sftp = opts.protocol == Protocol::Sftp;
+    let wire = opts.show_overhead.then(|| {
+        WireEstimate::new(
+            upload_result.as_ref().map(|upload| (upload, sftp)),
+            (
+                &download_result,
+                sftp && download_source == DownloadSource::File,
+            ),
+            formatter,
+        )
+    });
+    let summary = SpeedTestSummary {
         upload: upload_result,
         download: download_result,
-    })
+        baseline,
+        loaded_latency,
+        wire,
+    };
+    if summary.upload_disk_bound() == Some(true) {
+        warn!("Upload speed is significantly lower than in-memory transfer, remote disk may be the bottleneck");
+    }
+    if summary.download_disk_bound() == Some(true) {
+        warn!("Download speed is significantly lower than in-memory transfer, remote disk may be the bottleneck");
+    }
+    if let Some(ratio) = summary.asymmetry_ratio() {
+        if ratio > opts.asymmetry_warn {
+            warn!("Upload and download speeds differ by {ratio:.1}x, the link may be shaped, misconfigured or half-duplex");
+        }
+    }
+    Ok(summary)
+}
+
+// Run the echo test once per payload size, reporting the latency of each
+pub fn run_payload_sweep(
+    session: &Session,
+    opts: &Options,
+    sizes: &[u64],
+    formatter: &Formatter,
+) -> Result<Vec<Record>, String> {
+    info!("Running payload size sweep");
+    debug!("Payload sizes: {sizes:?}");
+    // Canonical mode terminals drop input beyond their line buffer
+    if !opts.echo_exec && sizes.iter().any(|&size| size >= PTY_LINE_LIMIT) {
+        warn!("Payloads of {PTY_LINE_LIMIT} bytes or more may not be echoed through a pseudo-terminal, consider --echo-exec");
+    }
+    let mut channel = start_echo_channel_with_retries(session, opts)?;
+    let write_buffer = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    let timeout = opts.echo_timeout.map(Duration::from_secs_f64);
+    let mut records = vec![];
+    for &size in sizes {
+        let payload = write_buffer
+            .iter()
+            .cycle()
+            .take(size as usize)
+            .copied()
+            .collect::<Vec<_>>();
+        let mut latencies = Vec::with_capacity(opts.char_count);
+        let start_time = Instant::now();
+        let progress_bar = new_progress_bar(
+            opts.char_count as u64,
+            &format!("Payload {}", formatter.format_size(size)),
+            false,
+        );
+        for n in 0..opts.char_count {
+            latencies.push(echo_payload_round_trip(&mut channel, &payload)?);
+            progress_bar.set_position((n as u64) + 1);
+            if timeout.is_some_and(|timeout| start_time.elapsed() > timeout) {
+                break;
+            }
+        }
+        progress_bar.finish_and_clear();
+        latencies.sort();
+        let result = EchoTestSummary::from_latencies(&latencies, &[], None, &[], formatter);
+        info!(
+            "Payload {}: Average {}, Median {}",
+            formatter.format_size(size),
+            result.avg_latency,
+            result.med_latency
+        );
+        records.push(Record::new(
+            "Payload sweep",
+            formatter.format_size(size),
+            format!("{} avg, {} median", result.avg_latency, result.med_latency),
+        ));
+    }
+    Ok(records)
+}
+
+// Run `true` over and over without a terminal or shell, measuring from
+// opening the channel until the command exits
+pub fn run_exec_probe(
+    session: &Session,
+    opts: &Options,
+    formatter: &Formatter,
+) -> Result<Vec<Record>, String> {
+    info!("Running exec latency probe");
+    let timeout = opts.echo_timeout.map(Duration::from_secs_f64);
+    let mut latencies = Vec::with_capacity(opts.char_count);
+    let start_time = Instant::now();
+    let progress_bar = new_progress_bar(opts.char_count as u64, "Exec probe", false);
+    for n in 0..opts.char_count {
+        let start = Instant::now();
+        let mut channel = open_with_retries(opts.channel_retries, || session.channel_session())
+            .map_err(channel_error)?;
+        send_env(&mut channel, opts);
+        channel.exec("true").map_err(|e| e.to_string())?;
+        channel.wait_close().map_err(|e| e.to_string())?;
+        latencies.push(start.elapsed().as_nanos());
+        // A forced command or restricted shell may run something else
+        let status = channel.exit_status().map_err(|e| e.to_string())?;
+        if status != 0 {
+            debug!("Remote `true` exited with status {status}");
+        }
+        progress_bar.set_position((n as u64) + 1);
+        if timeout.is_some_and(|timeout| start_time.elapsed() > timeout) {
+            break;
+        }
+    }
+    progress_bar.finish_and_clear();
+    latencies.sort();
+    let result = EchoTestSummary::from_latencies(
+        &latencies,
+        &opts.percentiles,
+        opts.trim_outliers,
+        &opts.latency_buckets,
+        formatter,
+    );
+    info!(
+        "Ran {} commands, Average {}, Median {}",
+        result.char_sent, result.avg_latency, result.med_latency
+    );
+    let mut records = vec![Record::new(
+        "Exec",
+        "Commands",
+        result.char_sent.to_string(),
+    )];
+    records.extend(result.to_records("Exec"));
+    Ok(records)
+}
+
+// Median round trip time of a few echoes, for when the echo test is skipped
+pub fn probe_rtt(session: &Session, opts: &Options) -> Result<Duration, String> {
+    debug!("Probing round trip time");
+    let mut channel = start_echo_channel_with_retries(session, opts)?;
+    let mut latencies = (0..RTT_PROBES)
+        .map(|_| echo_round_trip(&mut channel, b"x", !opts.no_echo_filter))
+        .collect::<Result<Vec<_>, _>>()?;
+    latencies.sort();
+    Ok(Duration::from_nanos(latencies[latencies.len() / 2] as u64))
+}
+
+// Chunk size covering the bandwidth-delay product of a fast link with the
+// given round trip time, so that each chunk keeps the pipe busy
+pub fn chunk_size_for_rtt(rtt: Duration) -> u64 {
+    let bdp = (rtt.as_secs_f64() * NOMINAL_BANDWIDTH) as u64;
+    bdp.next_power_of_two()
+        .clamp(MIN_AUTO_CHUNK_SIZE, MAX_AUTO_CHUNK_SIZE)
+}
+
+// Size of the remote file in bytes, or None if it does not exist
+fn remote_file_size(session: &Session, remote_file: &Path) -> Option<u64> {
+    match execute_remote_command(
+        session,
+        &format!("wc -c < {}", shell_quote(&remote_file.to_string_lossy())),
+    ) {
+        Ok((0, output)) => output.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+// Unique remote file name so that concurrent runs do not collide
+pub fn unique_remote_file() -> PathBuf {
+    PathBuf::from(format!(
+        "/tmp/sshping-{}-{:08x}.tmp",
+        process::id(),
+        random::<u32>()
+    ))
+}
+
+// Run a command on the remote, returning its exit status and output
+pub fn execute_remote_command(session: &Session, command: &str) -> Result<(i32, String), String> {
+    trace!("Executing remote command: {command:?}");
+    let mut channel = session.channel_session().map_err(channel_error)?;
+    channel.exec(command).map_err(|e| e.to_string())?;
+    let mut output = String::new();
+    channel
+        .read_to_string(&mut output)
+        .map_err(|e| e.to_string())?;
+    channel.wait_close().map_err(|e| e.to_string())?;
+    let code = channel.exit_status().map_err(|e| e.to_string())?;
+    Ok((code, output))
+}
+
+pub fn remove_remote_file(session: &Session, remote_file: &Path) -> Result<(), String> {
+    debug!("Removing remote file {remote_file:?}");
+    match execute_remote_command(
+        session,
+        &format!("rm -f {}", shell_quote(&remote_file.to_string_lossy())),
+    )? {
+        (0, _) => Ok(()),
+        (code, _) => Err(format!("rm exited with status {code}")),
+    }
+}
+
+// Remote tools the selected tests depend on, including removing the
+// remote file afterwards if CLEANUP
+fn required_tools(opts: &Options, cleanup: bool) -> Vec<String> {
+    let mut tools = vec![];
+    let mut require = |tool: &str| {
+        if !tools.iter().any(|required| required == tool) {
+            tools.push(tool.to_string());
+        }
+    };
+    let echo = opts.run_tests != Test::Speed || opts.loaded_latency;
+    if let Some(command) = opts.echo_cmd.split_whitespace().next().filter(|_| echo) {
+        require(command);
+    }
+    if opts.run_tests != Test::Echo {
+        if opts.protocol == Protocol::Scp {
+            require("scp");
+        }
+        if opts.reuse_remote_file {
+            require("wc");
+        }
+        if opts.download_source != DownloadSource::File || opts.diagnose_bottleneck {
+            // Time bound transfers read the device until they are stopped
+            require(match opts.speed_duration {
+                Some(_) => "cat",
+                None => "head",
+            });
+        }
+        if opts.diagnose_bottleneck {
+            require("cat");
+        }
+        if cleanup {
+            require("rm");
+        }
+    }
+    tools
+}
+
+// Tools the selected tests use when present but can do without
+fn optional_tools(opts: &Options) -> Vec<String> {
+    match opts.run_tests {
+        // Free space is checked before uploading if df is there
+        Test::Echo => vec![],
+        _ => vec!["df".to_string()],
+    }
+}
+
+// Whether the remote shell finds TOOL
+fn has_remote_tool(session: &Session, tool: &str) -> Result<bool, String> {
+    match execute_remote_command(session, &format!("command -v {}", shell_quote(tool)))? {
+        (0, path) => {
+            debug!("Found remote tool {tool}: {}", path.trim());
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+// Check that the remote has every tool the selected tests need
+pub fn run_preflight(session: &Session, opts: &Options, cleanup: bool) -> Result<(), String> {
+    info!("Running preflight check");
+    for tool in optional_tools(opts) {
+        if !has_remote_tool(session, &tool)? {
+            warn!("Remote tool {tool} is missing, some checks will be skipped");
+        }
+    }
+    let mut missing = vec![];
+    for tool in required_tools(opts, cleanup) {
+        if !has_remote_tool(session, &tool)? {
+            missing.push(tool);
+        }
+    }
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Missing remote tools: {}", missing.join(", ")))
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use clap::Parser;
+
+    use super::*;
+
+    fn options(args: &[&str]) -> Options {
+        let config = ["sshping", "--config", env!("CARGO_MANIFEST_DIR")];
+        Options::parse_from(config.iter().chain(args).chain(&["example"]))
+    }
+
+    #[test]
+    fn echo_filter_skips_shell_output() {
+        let mut output: &[u8] = b"\x1b[?2004l$ xy";
+        read_echo(&mut output, b"x", true).unwrap();
+        assert_eq!(output, b"y");
+
+        // Without filter any byte counts as the echo
+        let mut output: &[u8] = b"\x1b[?2004lx";
+        read_echo(&mut output, b"x", false).unwrap();
+        assert_eq!(output, b"[?2004lx");
+
+        // Output ending before the echo is an error
+        let mut output: &[u8] = b"$ ";
+        assert!(read_echo(&mut output, b"x", true).is_err());
+    }
+
+    #[test]
+    fn required_tools_without_duplicates() {
+        let opts = options(&["--echo-cmd", "cat", "--diagnose-bottleneck"]);
+        assert_eq!(required_tools(&opts, false), ["cat", "scp", "head"]);
+    }
+
+    #[test]
+    fn df_is_optional() {
+        let opts = options(&[]);
+        assert!(!required_tools(&opts, true).contains(&"df".to_string()));
+        assert_eq!(optional_tools(&opts), ["df"]);
+        assert!(optional_tools(&options(&["--run-tests", "echo"])).is_empty());
+    }
+
+    #[test]
+    fn required_tools_for_remote_file_handling() {
+        let opts = options(&["--remote-file", "/tmp/file", "--reuse-remote-file"]);
+        assert!(required_tools(&opts, false).contains(&"wc".to_string()));
+        assert!(!required_tools(&opts, false).contains(&"rm".to_string()));
+        assert!(required_tools(&options(&[]), true).contains(&"rm".to_string()));
+    }
 }
@@ -0,0 +1,131 @@
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::{cli::TimelineStyle, util::Formatter};
+
+// Width of the bar area in ASCII timeline
+const ASCII_WIDTH: usize = 60;
+// Dimensions of SVG timeline
+const SVG_LABEL_WIDTH: usize = 160;
+const SVG_BAR_WIDTH: usize = 600;
+const SVG_ROW_HEIGHT: usize = 24;
+
+struct Phase {
+    name: &'static str,
+    // Offset from the start of the run
+    start: Duration,
+    duration: Duration,
+}
+
+pub struct Timeline {
+    origin: Instant,
+    // Wall clock time at the origin, to tell when each phase happened
+    wall_origin: SystemTime,
+    phases: Vec<Phase>,
+}
+
+impl Timeline {
+    pub fn start() -> Self {
+        Self {
+            origin: Instant::now(),
+            wall_origin: SystemTime::now(),
+            phases: vec![],
+        }
+    }
+
+    pub fn record(&mut self, name: &'static str, start: Instant, duration: Duration) {
+        self.phases.push(Phase {
+            name,
+            start: start.saturating_duration_since(self.origin),
+            duration,
+        });
+    }
+
+    // Wall clock start and end of each phase
+    pub fn timestamps(&self) -> Vec<(&'static str, SystemTime, SystemTime)> {
+        self.phases
+            .iter()
+            .map(|phase| {
+                let start = self.wall_origin + phase.start;
+                (phase.name, start, start + phase.duration)
+            })
+            .collect()
+    }
+
+    pub fn render(&self, style: TimelineStyle, formatter: &Formatter) -> String {
+        match style {
+            TimelineStyle::Ascii => self.render_ascii(formatter),
+            TimelineStyle::Svg => self.render_svg(formatter),
+        }
+    }
+
+    fn total(&self) -> Duration {
+        self.phases
+            .iter()
+            .map(|phase| phase.start + phase.duration)
+            .max()
+            .unwrap_or_default()
+    }
+
+    // Scale a duration to the given width, relative to the whole run
+    fn scale(&self, duration: Duration, width: usize) -> usize {
+        let total = self.total().as_secs_f64();
+        if total == 0.0 {
+            return 0;
+        }
+        ((duration.as_secs_f64() / total) * width as f64).round() as usize
+    }
+
+    fn render_ascii(&self, formatter: &Formatter) -> String {
+        let name_width = self
+            .phases
+            .iter()
+            .map(|phase| phase.name.len())
+            .max()
+            .unwrap_or_default();
+        self.phases
+            .iter()
+            .map(|phase| {
+                let offset = self.scale(phase.start, ASCII_WIDTH).min(ASCII_WIDTH - 1);
+                let length = self
+                    .scale(phase.duration, ASCII_WIDTH)
+                    .clamp(1, ASCII_WIDTH - offset);
+                format!(
+                    "{:<name_width$} |{}{}{}| {}",
+                    phase.name,
+                    " ".repeat(offset),
+                    "\u{2588}".repeat(length),
+                    " ".repeat(ASCII_WIDTH - offset - length),
+                    formatter.format_duration(phase.duration),
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn render_svg(&self, formatter: &Formatter) -> String {
+        let width = SVG_LABEL_WIDTH + SVG_BAR_WIDTH;
+        let height = SVG_ROW_HEIGHT * self.phases.len();
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {width} {height}\" font-family=\"monospace\" font-size=\"12\">\n"
+        );
+        for (row, phase) in self.phases.iter().enumerate() {
+            let y = row * SVG_ROW_HEIGHT;
+            let x = SVG_LABEL_WIDTH + self.scale(phase.start, SVG_BAR_WIDTH);
+            let bar_width = self.scale(phase.duration, SVG_BAR_WIDTH).max(1);
+            svg += &format!(
+                "  <text x=\"4\" y=\"{}\">{}</text>\n",
+                y + SVG_ROW_HEIGHT * 2 / 3,
+                phase.name
+            );
+            svg += &format!(
+                "  <rect x=\"{x}\" y=\"{}\" width=\"{bar_width}\" height=\"{}\" fill=\"steelblue\"><title>{} {}</title></rect>\n",
+                y + 4,
+                SVG_ROW_HEIGHT - 8,
+                phase.name,
+                formatter.format_duration(phase.duration)
+            );
+        }
+        svg += "</svg>";
+        svg
+    }
+}
@@ -1,16 +1,121 @@
-use std::time::Duration;
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    io::Write,
+    path::Path,
+    process::{self, Command},
+    sync::Mutex,
+    thread::sleep,
+    time::{Duration, Instant},
+};
 
+use indicatif::{ProgressBar, ProgressState, ProgressStyle};
 use num_format::{Buffer, CustomFormat};
 use size::{Base, Size, Style};
 
+use crate::cli::{DurationStyle, ProgressDisplay};
+
+// How progress is drawn, along with the phases already summarized on the
+// status line
+static PROGRESS: Mutex<(ProgressDisplay, Vec<String>)> =
+    Mutex::new((ProgressDisplay::Bar, Vec::new()));
+
+pub fn set_progress_display(display: ProgressDisplay) {
+    PROGRESS.lock().unwrap().0 = display;
+}
+
+// Keep a finished phase on the status line of later phases
+pub fn push_progress_summary(summary: String) {
+    PROGRESS.lock().unwrap().1.push(summary);
+}
+
+fn get_progress_bar_style(name: &str, bytes: bool) -> ProgressStyle {
+    let counter = if bytes {
+        "{bytes}/{total_bytes}"
+    } else {
+        "{pos}/{len}"
+    };
+    ProgressStyle::default_bar()
+        .template(
+            &format!(
+                "{name} {{spinner:.green}} [{{elapsed_precise}}] [{{wide_bar:.cyan/blue}}] {counter} ({{eta}})"
+            )
+        )
+        .unwrap()
+        .with_key("eta", |state: &ProgressState, w: &mut dyn std::fmt::Write|
+            write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap()
+        )
+        .progress_chars("#>-")
+}
+
+fn get_progress_line_style(name: &str, bytes: bool, summaries: &[String]) -> ProgressStyle {
+    let counter = if bytes {
+        "{bytes}/{total_bytes}"
+    } else {
+        "{pos}/{len}"
+    };
+    let mut line = summaries.join(" | ");
+    if !line.is_empty() {
+        line += " | ";
+    }
+    // Escape braces so that summaries are not taken as template keys
+    let line = line.replace('{', "{{").replace('}', "}}");
+    ProgressStyle::with_template(&format!("{line}{}: {counter}", name.to_lowercase())).unwrap()
+}
+
+// Progress bar for a phase of LEN steps, counted in bytes or plain numbers
+pub fn new_progress_bar(len: u64, name: &str, bytes: bool) -> ProgressBar {
+    let progress = PROGRESS.lock().unwrap();
+    match progress.0 {
+        ProgressDisplay::Bar => {
+            ProgressBar::new(len).with_style(get_progress_bar_style(name, bytes))
+        }
+        ProgressDisplay::Line => {
+            ProgressBar::new(len).with_style(get_progress_line_style(name, bytes, &progress.1))
+        }
+        ProgressDisplay::None => ProgressBar::hidden(),
+    }
+}
+
+// Spinner for phases of unknown length, showing what is going on
+pub fn new_spinner() -> ProgressBar {
+    let progress = PROGRESS.lock().unwrap();
+    let spinner = match progress.0 {
+        ProgressDisplay::Bar => ProgressBar::new_spinner()
+            .with_style(ProgressStyle::with_template("{spinner:.green} {msg}").unwrap()),
+        ProgressDisplay::Line => {
+            let mut line = progress.1.join(" | ");
+            if !line.is_empty() {
+                line += " | ";
+            }
+            let line = line.replace('{', "{{").replace('}', "}}");
+            ProgressBar::new_spinner()
+                .with_style(ProgressStyle::with_template(&format!("{line}{{msg}}")).unwrap())
+        }
+        ProgressDisplay::None => return ProgressBar::hidden(),
+    };
+    spinner.enable_steady_tick(Duration::from_millis(100));
+    spinner
+}
+
 pub struct Formatter {
     // Formatter style for large number
     // Only used when human_readable is false
     format: Option<CustomFormat>,
+    duration_style: DurationStyle,
+    // Fixed number of decimal places for human-friendly sizes
+    precision: Option<usize>,
+    // Append the exact byte count to human-friendly sizes
+    exact_bytes: bool,
 }
 
 impl Formatter {
-    pub fn new(human_readable: bool, delimit: Option<char>) -> Self {
+    pub fn new(
+        human_readable: bool,
+        delimit: Option<char>,
+        duration_style: DurationStyle,
+        precision: Option<usize>,
+        exact_bytes: bool,
+    ) -> Self {
         let format = (!human_readable).then(|| {
             CustomFormat::builder()
                 .separator(
@@ -22,18 +127,25 @@ impl Formatter {
                 .unwrap()
         });
 
-        Self { format }
+        Self {
+            format,
+            duration_style,
+            precision,
+            exact_bytes,
+        }
     }
 
     pub fn format_duration(&self, time: Duration) -> String {
-        if let Some(format) = &self.format {
+        if self.duration_style == DurationStyle::Seconds {
+            format!("{}s", time.as_secs_f64())
+        } else if let Some(format) = &self.format {
             let mut buffer = Buffer::new();
             buffer.write_formatted(&time.as_nanos(), format);
             buffer.as_str().to_string() + "ns"
         } else {
             let formatted = humantime::format_duration(time).to_string();
             let parts = formatted.split(" ").collect::<Vec<&str>>();
-            if parts.len() > 2 {
+            if self.duration_style == DurationStyle::Compact && parts.len() > 2 {
                 parts[..2].join(" ")
             } else {
                 formatted
@@ -42,10 +154,38 @@ impl Formatter {
     }
 
     pub fn format_size(&self, size: u64) -> String {
+        let formatted = self.format_bytes(size);
+        // Plain numbers and sizes below a kilobyte are exact already
+        if self.exact_bytes && self.format.is_none() && size >= 1000 {
+            format!("{formatted} ({size} bytes)")
+        } else {
+            formatted
+        }
+    }
+
+    pub fn format_rate(&self, bytes_per_sec: f64) -> String {
+        self.format_bytes(bytes_per_sec as u64) + "/s"
+    }
+
+    fn format_bytes(&self, size: u64) -> String {
         if let Some(format) = &self.format {
             let mut buffer = Buffer::new();
             buffer.write_formatted(&size, format);
             buffer.as_str().to_string() + " B"
+        } else if let Some(precision) = self.precision {
+            // Same base 10 units as below, with a fixed number of decimals
+            let units = ["B", "KB", "MB", "GB", "TB", "PB", "EB"];
+            let mut value = size as f64;
+            let mut unit = 0;
+            while value >= 1000.0 && unit < units.len() - 1 {
+                value /= 1000.0;
+                unit += 1;
+            }
+            if unit == 0 {
+                format!("{size} B")
+            } else {
+                format!("{value:.precision$} {}", units[unit])
+            }
         } else {
             Size::from_bytes(size)
                 .format()
@@ -55,3 +195,193 @@ impl Formatter {
         }
     }
 }
+
+// Substitute %-tokens in the string, `%%` being a literal percent sign
+pub fn expand_tokens(s: &str, tokens: &[(char, &str)]) -> Result<String, String> {
+    let mut expanded = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '%' {
+            expanded.push(ch);
+            continue;
+        }
+        match chars.next() {
+            Some('%') => expanded.push('%'),
+            Some(token) => match tokens.iter().find(|(t, _)| *t == token) {
+                Some((_, value)) => expanded.push_str(value),
+                None => return Err(format!("Unknown token %{token} in {s:?}")),
+            },
+            None => return Err(format!("Incomplete token at the end of {s:?}")),
+        }
+    }
+    Ok(expanded)
+}
+
+// Substitute {name} placeholders with field values, `{{` and `}}` being
+// literal braces
+pub fn render_template(template: &str, fields: &[(&str, String)]) -> Result<String, String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(index) = rest.find(['{', '}']) {
+        rendered.push_str(&rest[..index]);
+        let brace = &rest[index..index + 1];
+        rest = &rest[index + 1..];
+        if let Some(after) = rest.strip_prefix(brace) {
+            rendered.push_str(brace);
+            rest = after;
+            continue;
+        }
+        if brace == "}" {
+            return Err(format!("Unmatched }} in {template:?}"));
+        }
+        let (name, after) = rest
+            .split_once('}')
+            .ok_or_else(|| format!("Unclosed placeholder in {template:?}"))?;
+        match fields.iter().find(|(field, _)| *field == name) {
+            Some((_, value)) => rendered.push_str(value),
+            None => return Err(format!("Unknown placeholder {{{name}}} in {template:?}")),
+        }
+        rest = after;
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+// Escape a string for embedding in JSON
+pub fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            ch if ch.is_control() => escaped.push_str(&format!("\\u{:04x}", ch as u32)),
+            ch => escaped.push(ch),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+// Take an exclusive lock on the file, waiting up to the timeout for another
+// run to release it. The lock lasts as long as the returned file is open and
+// is released by the system however the process exits
+pub fn acquire_lock(path: &Path, timeout: Option<Duration>) -> Result<File, String> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .map_err(|e| e.to_string())?;
+    let start = Instant::now();
+    loop {
+        match file.try_lock() {
+            Ok(()) => break,
+            Err(TryLockError::WouldBlock) => {
+                if timeout.is_none_or(|timeout| start.elapsed() >= timeout) {
+                    return Err("Another sshping run holds the lock".to_string());
+                }
+                sleep(Duration::from_millis(100));
+            }
+            Err(TryLockError::Error(e)) => return Err(e.to_string()),
+        }
+    }
+    // Record who holds the lock, for whoever finds it taken
+    file.set_len(0).map_err(|e| e.to_string())?;
+    writeln!(file, "{}", process::id()).map_err(|e| e.to_string())?;
+    Ok(file)
+}
+
+// Run the command with the platform shell and return its exit status
+pub fn run_local_command(command: &str) -> Result<i32, String> {
+    let status = shell_command(command).status().map_err(|e| e.to_string())?;
+    Ok(status.code().unwrap_or(-1))
+}
+
+// Quote S for a POSIX shell on the remote so that it is taken literally
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+// Local command run by the platform shell
+pub fn shell_command(command: &str) -> Command {
+    #[cfg(windows)]
+    let mut shell = {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    };
+    #[cfg(not(windows))]
+    let mut shell = {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell.arg(command);
+    shell
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn human() -> Formatter {
+        Formatter::new(true, Some(','), DurationStyle::Full, None, false)
+    }
+
+    fn delimited() -> Formatter {
+        Formatter::new(false, Some(','), DurationStyle::Full, None, false)
+    }
+
+    #[test]
+    fn quote_for_shell() {
+        assert_eq!(shell_quote("/tmp/file"), "'/tmp/file'");
+        assert_eq!(
+            shell_quote("/tmp/it's; rm -rf ~"),
+            r"'/tmp/it'\''s; rm -rf ~'"
+        );
+    }
+
+    #[test]
+    fn human_duration() {
+        let time = Duration::from_nanos(1_042_100);
+        assert_eq!(human().format_duration(time), "1ms 42us 100ns");
+        let compact = Formatter::new(true, None, DurationStyle::Compact, None, false);
+        assert_eq!(compact.format_duration(time), "1ms 42us");
+    }
+
+    #[test]
+    fn delimited_duration() {
+        let time = Duration::from_nanos(1_042_100);
+        assert_eq!(delimited().format_duration(time), "1,042,100ns");
+        let plain = Formatter::new(false, None, DurationStyle::Full, None, false);
+        assert_eq!(plain.format_duration(time), "1042100ns");
+        // Seconds ignore the human readable setting
+        let seconds = Formatter::new(false, Some(','), DurationStyle::Seconds, None, false);
+        assert_eq!(seconds.format_duration(time), "0.0010421s");
+    }
+
+    #[test]
+    fn human_size() {
+        assert_eq!(human().format_size(999), "999 B");
+        assert_eq!(human().format_size(8_000_000), "8.00 MB");
+        let precise = Formatter::new(true, None, DurationStyle::Full, Some(1), false);
+        assert_eq!(precise.format_size(1_234_567), "1.2 MB");
+        assert_eq!(precise.format_size(999), "999 B");
+        let exact = Formatter::new(true, None, DurationStyle::Full, Some(1), true);
+        assert_eq!(exact.format_size(8_388_608), "8.4 MB (8388608 bytes)");
+        assert_eq!(exact.format_rate(8_388_608.0), "8.4 MB/s");
+    }
+
+    #[test]
+    fn delimited_size() {
+        assert_eq!(delimited().format_size(1_234_567), "1,234,567 B");
+        assert_eq!(delimited().format_rate(1_234_567.9), "1,234,567 B/s");
+        // Plain numbers are exact already
+        let exact = Formatter::new(false, Some('_'), DurationStyle::Full, None, true);
+        assert_eq!(exact.format_size(1_234_567), "1_234_567 B");
+    }
+}